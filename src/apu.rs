@@ -0,0 +1,623 @@
+// https://gbdev.io/pandocs/Audio_Registers.html
+mod mixer;
+mod noise;
+mod square;
+mod wave;
+
+use mixer::Mixer;
+use noise::Noise;
+use square::Square;
+use wave::Wave;
+
+const NR52_POWER: u8 = 0b1000_0000;
+
+pub use mixer::{CLOCK_RATE, DEFAULT_SAMPLE_RATE};
+
+const WAVE_RAM_START: u16 = 0xff30;
+const WAVE_RAM_END: u16 = 0xff3f;
+
+/// Bits of NR10-NR52 that always read back as 1, indexed from 0xFF10.
+const READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // NR20-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+];
+
+/// NRx4 bits shared by all channels.
+const NRX4_TRIGGER: u8 = 0b1000_0000;
+const NRX4_LENGTH_ENABLE: u8 = 0b0100_0000;
+
+// https://gbdev.io/pandocs/Audio_Registers.html#ff76--pcm12-cgb-mode-only-digital-outputs-1--2-read-only
+const PCM12_ADDR: u16 = 0xff76;
+const PCM34_ADDR: u16 = 0xff77;
+
+const NR50: usize = 0x14;
+const NR51: usize = 0x15;
+
+/// Channels are mixed at 1 MHz, the rate most of their timers run at.
+const MIX_CYCLES: u32 = 4;
+
+/// Length timer, which turns its channel off when it runs out.
+struct Length {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new(max: u16) -> Self {
+        Self {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    /// Loads the counter from the initial length timer bits of NRx1.
+    fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Returns whether the counter just ran out.
+    fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+}
+
+/// Volume envelope of the square and noise channels, set through NRx2.
+struct Envelope {
+    register: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            register: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    /// With the upper five bits of NRx2 clear the channel's DAC is off.
+    fn dac_enabled(&self) -> bool {
+        self.register & 0xf8 != 0
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+        if self.register & 0x08 != 0 {
+            self.volume = (self.volume + 1).min(15);
+        } else {
+            self.volume = self.volume.saturating_sub(1);
+        }
+    }
+}
+
+/// A write to an APU register, for logging music.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RegisterWrite {
+    /// APU cycles since power on.
+    pub cycle: u64,
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// What a channel is doing right now, for debugging.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ChannelState {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    /// 0-15: the envelope volume, or the wave channel's output level.
+    pub volume: u8,
+    /// The tone's frequency, or how often the noise LFSR is clocked.
+    pub frequency_hz: f32,
+    /// The DAC output in -1.0..=1.0.
+    pub amplitude: f32,
+    pub muted: bool,
+}
+
+impl std::fmt::Display for ChannelState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} dac={} vol={:2} {:9.2} Hz amp={:+.3}{}",
+            if self.enabled { "on " } else { "off" },
+            if self.dac_enabled { "on " } else { "off" },
+            self.volume,
+            self.frequency_hz,
+            self.amplitude,
+            if self.muted { " (muted)" } else { "" }
+        )
+    }
+}
+
+pub struct Apu {
+    power: bool,
+    /// NR10-NR51 as last written, which is what reads return.
+    registers: [u8; 0x16],
+    wave_ram: [u8; 16],
+    /// Step 0-7 of the 512 Hz frame sequencer.
+    frame_step: u8,
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    mixer: Mixer,
+    /// Each channel resampled on its own, before panning and volume.
+    channel_mixers: Option<Box<[Mixer; 4]>>,
+    muted: [bool; 4],
+    soloed: [bool; 4],
+    cycles: u64,
+    writes: Option<Vec<RegisterWrite>>,
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        let mut apu = Self {
+            power: true,
+            registers: [0; 0x16],
+            wave_ram: [0; 16],
+            frame_step: 0,
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            mixer: Mixer::new(DEFAULT_SAMPLE_RATE),
+            channel_mixers: None,
+            muted: [false; 4],
+            soloed: [false; 4],
+            cycles: 0,
+            writes: None,
+        };
+        // The state the boot ROM leaves behind, its chime on channel 1
+        // having faded out.
+        let boot = [
+            (0xff10, 0x80),
+            (0xff11, 0xbf),
+            (0xff12, 0xf3),
+            (0xff16, 0x3f),
+            (0xff1a, 0x7f),
+            (0xff1b, 0xff),
+            (0xff1c, 0x9f),
+            (0xff20, 0xff),
+            (0xff24, 0x77),
+            (0xff25, 0xf3),
+        ];
+        for (addr, byte) in boot {
+            apu.write_register(addr, byte);
+        }
+        apu.square1.set_enabled(true);
+        apu
+    }
+
+    /// The digital output, 0-15, of each channel in NR52 order. Channels that
+    /// are off output 0.
+    pub fn channel_outputs(&self) -> [u8; 4] {
+        [
+            self.square1.output(),
+            self.square2.output(),
+            self.wave.output(&self.wave_ram),
+            self.noise.output(),
+        ]
+    }
+
+    /// Cycles the APU has run for, at normal speed.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Starts or stops keeping every register write for `take_writes`.
+    pub fn set_write_logging(&mut self, enabled: bool) {
+        self.writes = enabled.then(Vec::new);
+    }
+
+    /// Returns and clears the register writes logged since the last call.
+    pub fn take_writes(&mut self) -> Vec<RegisterWrite> {
+        self.writes.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// NR10-NR51 as last written, including bits that read back as 1, and
+    /// wave RAM.
+    pub fn raw_registers(&self) -> ([u8; 0x16], [u8; 16]) {
+        (self.registers, self.wave_ram)
+    }
+
+    /// Leaves channel 0-3 out of the mixed output.
+    pub fn set_channel_muted(&mut self, channel: usize, muted: bool) {
+        self.muted[channel] = muted;
+    }
+
+    /// While any channel is soloed, only soloed channels are mixed.
+    pub fn set_channel_solo(&mut self, channel: usize, solo: bool) {
+        self.soloed[channel] = solo;
+    }
+
+    /// Whether channel 0-3 is left out of the mixed output, either muted or
+    /// because others are soloed.
+    pub fn is_channel_muted(&self, channel: usize) -> bool {
+        self.muted[channel] || (self.soloed.contains(&true) && !self.soloed[channel])
+    }
+
+    pub fn channel_states(&self) -> [ChannelState; 4] {
+        let enabled = [
+            self.square1.enabled(),
+            self.square2.enabled(),
+            self.wave.enabled(),
+            self.noise.enabled(),
+        ];
+        let volume = [
+            self.square1.volume(),
+            self.square2.volume(),
+            self.wave.volume(),
+            self.noise.volume(),
+        ];
+        let frequency_hz = [
+            self.square1.frequency_hz(),
+            self.square2.frequency_hz(),
+            self.wave.frequency_hz(),
+            self.noise.frequency_hz(),
+        ];
+        let dacs = self.dacs_enabled();
+        let amplitude = self.dac_outputs();
+        std::array::from_fn(|i| ChannelState {
+            enabled: enabled[i],
+            dac_enabled: dacs[i],
+            volume: volume[i],
+            frequency_hz: frequency_hz[i],
+            amplitude: amplitude[i],
+            muted: self.is_channel_muted(i),
+        })
+    }
+
+    /// Starts producing samples at `rate` Hz, dropping any not read yet.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.mixer = Mixer::new(rate);
+        if self.channel_mixers.is_some() {
+            self.set_channel_capture(true);
+        }
+    }
+
+    /// Also produces samples for each channel on its own, to be read with
+    /// `read_channel_samples`.
+    pub fn set_channel_capture(&mut self, capture: bool) {
+        let rate = self.sample_rate();
+        self.channel_mixers = capture.then(|| Box::new(std::array::from_fn(|_| Mixer::new(rate))));
+    }
+
+    /// Like `read_samples`, for channel 0-3 alone and at half volume, with
+    /// the same sample on both sides. Returns 0 unless channel capture is on.
+    pub fn read_channel_samples(&mut self, channel: usize, out: &mut [f32]) -> usize {
+        match &mut self.channel_mixers {
+            Some(mixers) => mixers[channel].read_samples(out),
+            None => 0,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.mixer.sample_rate()
+    }
+
+    /// Number of samples waiting to be read, counting left and right
+    /// separately.
+    pub fn samples_available(&self) -> usize {
+        self.mixer.samples_available()
+    }
+
+    /// Moves the oldest samples into `out` as interleaved left and right
+    /// pairs in -1.0..=1.0 and returns how many were written. At most a
+    /// second of audio is kept for a reader that falls behind.
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        self.mixer.read_samples(out)
+    }
+
+    /// Whether each channel's DAC is on, in NR52 order.
+    pub fn dacs_enabled(&self) -> [bool; 4] {
+        [
+            self.square1.dac_enabled(),
+            self.square2.dac_enabled(),
+            self.wave.dac_enabled(),
+            self.noise.dac_enabled(),
+        ]
+    }
+
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff10..=0xff25 => {
+                let index = (addr - 0xff10) as usize;
+                READ_MASKS[index] | self.registers[index]
+            }
+            0xff26 => {
+                let status = [
+                    self.square1.enabled(),
+                    self.square2.enabled(),
+                    self.wave.enabled(),
+                    self.noise.enabled(),
+                ]
+                .iter()
+                .enumerate()
+                .fold(0, |bits, (i, &on)| bits | (on as u8) << i);
+                READ_MASKS[0x16] | (self.power as u8) << 7 | status
+            }
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave_ram[(addr - WAVE_RAM_START) as usize],
+            // The digital outputs of two channels each, low nibble first.
+            // Only mapped on CGB.
+            PCM12_ADDR | PCM34_ADDR => {
+                let outputs = self.channel_outputs();
+                let first = (addr - PCM12_ADDR) as usize * 2;
+                outputs[first] | outputs[first + 1] << 4
+            }
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, byte: u8) {
+        if let Some(writes) = &mut self.writes {
+            writes.push(RegisterWrite {
+                cycle: self.cycles,
+                addr,
+                value: byte,
+            });
+        }
+        match addr {
+            0xff26 => self.set_power(byte & NR52_POWER != 0),
+            // Everything but NR52 and wave RAM ignores writes while off.
+            0xff10..=0xff25 if self.power => {
+                self.registers[(addr - 0xff10) as usize] = byte;
+                if matches!(addr, 0xff14 | 0xff19 | 0xff1e | 0xff23) && byte & NRX4_TRIGGER != 0 {
+                    let channel = (addr - 0xff10) / 5 + 1;
+                    log::trace!(target: "apu", "CH{} triggered", channel);
+                }
+                match addr {
+                    0xff10..=0xff14 => self.square1.write((addr - 0xff10) as usize, byte),
+                    0xff15..=0xff19 => self.square2.write((addr - 0xff15) as usize, byte),
+                    0xff1a..=0xff1e => self.wave.write((addr - 0xff1a) as usize, byte),
+                    0xff1f..=0xff23 => self.noise.write((addr - 0xff1f) as usize, byte),
+                    _ => {}
+                }
+            }
+            WAVE_RAM_START..=WAVE_RAM_END => self.wave_ram[(addr - WAVE_RAM_START) as usize] = byte,
+            _ => {}
+        }
+    }
+
+    /// Advances the channels by `cycles` cycles at normal speed.
+    pub fn tick(&mut self, mut cycles: u32) {
+        self.cycles += cycles as u64;
+        while cycles > 0 {
+            let step = cycles.min(MIX_CYCLES);
+            cycles -= step;
+            if self.power {
+                self.square1.tick(step);
+                self.square2.tick(step);
+                self.wave.tick(step);
+                self.noise.tick(step);
+            }
+            let analog = self.dac_outputs();
+            let (left, right) = self.amplitudes(analog);
+            self.mixer.set_amplitudes(left, right);
+            self.mixer.advance(step);
+
+            if let Some(mixers) = &mut self.channel_mixers {
+                for (mixer, amplitude) in mixers.iter_mut().zip(analog) {
+                    mixer.set_amplitudes(amplitude / 2.0, amplitude / 2.0);
+                    mixer.advance(step);
+                }
+            }
+        }
+    }
+
+    /// The analog output of each channel's DAC, which maps 0 to 1.0 and 15
+    /// to -1.0. A DAC that's off outputs 0.
+    fn dac_outputs(&self) -> [f32; 4] {
+        if !self.power {
+            return [0.0; 4];
+        }
+        let outputs = self.channel_outputs();
+        let dacs = self.dacs_enabled();
+        std::array::from_fn(|i| {
+            if dacs[i] {
+                1.0 - outputs[i] as f32 / 7.5
+            } else {
+                0.0
+            }
+        })
+    }
+
+    /// Mixes the DAC outputs through NR51 panning and the NR50 master volume
+    /// into left and right amplitudes in -1.0..=1.0.
+    fn amplitudes(&self, analog: [f32; 4]) -> (f32, f32) {
+        let (nr50, nr51) = (self.registers[NR50], self.registers[NR51]);
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, analog) in analog.into_iter().enumerate() {
+            if self.is_channel_muted(i) {
+                continue;
+            }
+            if nr51 & (0x10 << i) != 0 {
+                left += analog;
+            }
+            if nr51 & (0x01 << i) != 0 {
+                right += analog;
+            }
+        }
+        let left_volume = ((nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (nr50 & 0x07) as f32 + 1.0;
+        (left * left_volume / 32.0, right * right_volume / 32.0)
+    }
+
+    /// Clocked at 512 Hz by DIV. Lengths run at 256 Hz, the sweep at 128 Hz
+    /// and envelopes at 64 Hz.
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.power {
+            return;
+        }
+        if self.frame_step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    /// Turning the APU off clears NR10-NR51 and silences every channel.
+    fn set_power(&mut self, power: bool) {
+        if power != self.power {
+            log::debug!(target: "apu", "Power {}", if power { "on" } else { "off" });
+        }
+        if self.power && !power {
+            self.registers = [0; 0x16];
+            self.square1 = Square::new(true);
+            self.square2 = Square::new(false);
+            self.wave = Wave::new();
+            self.noise = Noise::new();
+        } else if !self.power && power {
+            self.frame_step = 0;
+        }
+        self.power = power;
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_counter_stops_channel() {
+        let mut apu = Apu::new();
+        apu.write_register(0xff17, 0xf0);
+        apu.write_register(0xff16, 0x3e);
+        apu.write_register(0xff19, NRX4_TRIGGER | NRX4_LENGTH_ENABLE);
+        assert_eq!(apu.read_register(0xff26) & 0b0010, 0b0010);
+
+        apu.frame_step = 0;
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_register(0xff26) & 0b0010, 0b0010);
+        apu.clock_frame_sequencer();
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_register(0xff26) & 0b0010, 0);
+    }
+
+    #[test]
+    fn test_panning_and_volume() {
+        let mut apu = Apu::new();
+        apu.write_register(0xff12, 0x00);
+        apu.write_register(0xff24, 0x70);
+        apu.write_register(0xff25, 0x02);
+        apu.write_register(0xff17, 0xf0);
+        apu.write_register(0xff19, NRX4_TRIGGER);
+        apu.square2.set_enabled(true);
+        assert_eq!(apu.amplitudes(apu.dac_outputs()), (0.0, 1.0 / 32.0));
+
+        apu.write_register(0xff25, 0x20);
+        assert_eq!(apu.amplitudes(apu.dac_outputs()), (8.0 / 32.0, 0.0));
+
+        apu.tick(CLOCK_RATE / 100);
+        let frames = apu.samples_available() / 2;
+        assert!((440..=441).contains(&frames), "{} frames", frames);
+    }
+
+    #[test]
+    fn test_mute_solo_and_pcm() {
+        let mut apu = Apu::new();
+        apu.write_register(0xff12, 0xf0);
+        apu.write_register(0xff14, NRX4_TRIGGER);
+        apu.write_register(0xff16, 0x80);
+        apu.write_register(0xff17, 0xa0);
+        apu.write_register(0xff19, NRX4_TRIGGER);
+        apu.write_register(0xff25, 0x30);
+        assert_eq!(apu.read_register(0xff76), 0xaf);
+        assert_eq!(apu.read_register(0xff77), 0x00);
+
+        let analog = apu.dac_outputs();
+        assert_eq!(
+            apu.amplitudes(analog),
+            ((analog[0] + analog[1]) * 8.0 / 32.0, 0.0)
+        );
+        apu.set_channel_solo(1, true);
+        assert!(apu.is_channel_muted(0));
+        assert_eq!(apu.amplitudes(analog).0, analog[1] * 8.0 / 32.0);
+        apu.set_channel_muted(1, true);
+        assert_eq!(apu.amplitudes(analog), (0.0, 0.0));
+
+        let states = apu.channel_states();
+        assert_eq!(states[1].volume, 0x0a);
+        assert!(states[1].muted);
+        assert_eq!(states[0].frequency_hz, 64.0);
+    }
+
+    #[test]
+    fn test_power_off_clears_registers() {
+        let mut apu = Apu::new();
+        apu.write_register(0xff30, 0x12);
+        apu.write_register(0xff26, 0x00);
+        assert_eq!(apu.read_register(0xff26), 0x70);
+        assert_eq!(apu.read_register(0xff24), 0x00);
+        assert_eq!(apu.read_register(0xff11), 0x3f);
+        assert_eq!(apu.read_register(0xff30), 0x12);
+
+        apu.write_register(0xff24, 0x77);
+        assert_eq!(apu.read_register(0xff24), 0x00);
+        apu.write_register(0xff26, NR52_POWER);
+        apu.write_register(0xff24, 0x77);
+        assert_eq!(apu.read_register(0xff24), 0x77);
+    }
+
+    #[test]
+    fn test_envelope_and_dac() {
+        let mut apu = Apu::new();
+        apu.write_register(0xff21, 0xa1);
+        apu.write_register(0xff23, NRX4_TRIGGER);
+        assert_eq!(apu.noise.envelope.volume, 0x0a);
+        apu.frame_step = 7;
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.noise.envelope.volume, 0x09);
+
+        // Turning the DAC off turns the channel off.
+        apu.write_register(0xff21, 0x00);
+        assert_eq!(apu.read_register(0xff26) & 0b1000, 0);
+    }
+}
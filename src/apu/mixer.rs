@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use once_cell::sync::Lazy;
+
+/// The APU's clock at normal speed.
+pub const CLOCK_RATE: u32 = 4_194_304;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// Taps of the band-limited step, and how many fractional positions between
+/// two output samples it's precomputed for.
+const KERNEL_WIDTH: usize = 16;
+const KERNEL_PHASES: usize = 64;
+/// Cutoff of the low-pass, relative to the output rate.
+const CUTOFF: f64 = 0.45;
+
+/// Seconds of audio kept before the oldest samples are dropped.
+const BUFFER_SECONDS: usize = 1;
+
+/// Windowed-sinc impulses, normalised to sum to 1, for each phase. Adding a
+/// change in amplitude through one of these and summing the result gives a
+/// step without the aliasing of a hard edge.
+static KERNEL: Lazy<Vec<[f32; KERNEL_WIDTH]>> = Lazy::new(|| {
+    (0..KERNEL_PHASES)
+        .map(|phase| {
+            let frac = phase as f64 / KERNEL_PHASES as f64;
+            let mut taps = [0.0; KERNEL_WIDTH];
+            let mut sum = 0.0;
+            for (k, tap) in taps.iter_mut().enumerate() {
+                let x = k as f64 - frac - (KERNEL_WIDTH / 2) as f64;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (2.0 * PI * CUTOFF * x).sin() / (2.0 * PI * CUTOFF * x)
+                };
+                // Blackman window over the width of the kernel.
+                let w = (x + (KERNEL_WIDTH / 2) as f64) / KERNEL_WIDTH as f64;
+                let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+                let value = sinc * window.max(0.0);
+                *tap = value as f32;
+                sum += value;
+            }
+            taps.iter_mut().for_each(|tap| *tap /= sum as f32);
+            taps
+        })
+        .collect()
+});
+
+/// One side of the stereo output.
+struct Side {
+    /// Amplitude changes spread over the output samples they affect, starting
+    /// at the oldest sample not yet output.
+    deltas: Vec<f32>,
+    level: f32,
+    amplitude: f32,
+    /// Charge of the capacitor that blocks the DC offset, as on hardware.
+    capacitor: f32,
+}
+
+impl Side {
+    fn new() -> Self {
+        Self {
+            deltas: vec![0.0; KERNEL_WIDTH],
+            level: 0.0,
+            amplitude: 0.0,
+            capacitor: 0.0,
+        }
+    }
+
+    fn set_amplitude(&mut self, position: f64, amplitude: f32) {
+        let delta = amplitude - self.amplitude;
+        if delta == 0.0 {
+            return;
+        }
+        self.amplitude = amplitude;
+
+        let start = position as usize;
+        let phase = ((position - start as f64) * KERNEL_PHASES as f64) as usize;
+        if self.deltas.len() < start + KERNEL_WIDTH {
+            self.deltas.resize(start + KERNEL_WIDTH, 0.0);
+        }
+        for (k, tap) in KERNEL[phase].iter().enumerate() {
+            self.deltas[start + k] += delta * tap;
+        }
+    }
+
+    /// Finishes the first `count` samples and passes them through the
+    /// high-pass filter.
+    fn drain(&mut self, count: usize, charge_factor: f32) -> Vec<f32> {
+        if self.deltas.len() < count + KERNEL_WIDTH {
+            self.deltas.resize(count + KERNEL_WIDTH, 0.0);
+        }
+        self.deltas
+            .drain(..count)
+            .map(|delta| {
+                self.level += delta;
+                let out = self.level - self.capacitor;
+                self.capacitor = self.level - out * charge_factor;
+                out
+            })
+            .collect()
+    }
+}
+
+/// Turns the APU's left and right amplitudes, sampled at its own clock, into
+/// stereo samples at the host's rate.
+pub(super) struct Mixer {
+    sample_rate: u32,
+    /// Output samples per APU cycle.
+    step: f64,
+    charge_factor: f32,
+    /// Time in output samples since the oldest sample not yet output.
+    position: f64,
+    left: Side,
+    right: Side,
+    /// Interleaved left and right samples waiting to be read.
+    samples: VecDeque<f32>,
+}
+
+impl Mixer {
+    pub(super) fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            step: sample_rate as f64 / CLOCK_RATE as f64,
+            charge_factor: 0.999958f32.powf(CLOCK_RATE as f32 / sample_rate as f32),
+            position: 0.0,
+            left: Side::new(),
+            right: Side::new(),
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub(super) fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Sets the amplitudes, each in -1.0..=1.0, from now on.
+    pub(super) fn set_amplitudes(&mut self, left: f32, right: f32) {
+        self.left.set_amplitude(self.position, left);
+        self.right.set_amplitude(self.position, right);
+    }
+
+    /// Moves time forward by `cycles` APU cycles.
+    pub(super) fn advance(&mut self, cycles: u32) {
+        self.position += cycles as f64 * self.step;
+        // No later change can reach back before the current position.
+        let count = self.position as usize;
+        if count == 0 {
+            return;
+        }
+        self.position -= count as f64;
+
+        let left = self.left.drain(count, self.charge_factor);
+        let right = self.right.drain(count, self.charge_factor);
+        for (l, r) in left.into_iter().zip(right) {
+            self.samples.push_back(l);
+            self.samples.push_back(r);
+        }
+
+        let capacity = self.sample_rate as usize * 2 * BUFFER_SECONDS;
+        if self.samples.len() > capacity {
+            let excess = self.samples.len() - capacity;
+            self.samples.drain(..excess);
+        }
+    }
+
+    pub(super) fn samples_available(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub(super) fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        // Only whole stereo frames, so left and right never swap.
+        let count = out.len().min(self.samples.len()) & !1;
+        for (dest, sample) in out.iter_mut().zip(self.samples.drain(..count)) {
+            *dest = sample;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_count_follows_rate() {
+        let mut mixer = Mixer::new(48_000);
+        for _ in 0..CLOCK_RATE / 4 / 10 {
+            mixer.advance(4);
+        }
+        let frames = mixer.samples_available() / 2;
+        assert!((4799..=4800).contains(&frames), "{} frames", frames);
+    }
+
+    #[test]
+    fn test_step_is_band_limited_and_decays() {
+        let mut mixer = Mixer::new(DEFAULT_SAMPLE_RATE);
+        mixer.set_amplitudes(0.5, 0.0);
+        mixer.advance(CLOCK_RATE / 10);
+        let mut out = vec![0.0; mixer.samples_available()];
+        let count = mixer.read_samples(&mut out);
+        assert_eq!(count, out.len());
+
+        let left: Vec<f32> = out.iter().step_by(2).copied().collect();
+        assert!(out.iter().skip(1).step_by(2).all(|&r| r == 0.0));
+        // The edge rises over a few samples instead of at once...
+        assert!(left[KERNEL_WIDTH / 2] > 0.0 && left[KERNEL_WIDTH / 2] < 0.5);
+        let peak = left.iter().cloned().fold(0.0, f32::max);
+        assert!(peak > 0.45 && peak < 0.6);
+        // ...and the high-pass filter pulls the DC level back to 0.
+        assert!(left.last().unwrap().abs() < 0.01);
+        assert_eq!(mixer.samples_available(), 0);
+    }
+}
@@ -0,0 +1,130 @@
+// https://gbdev.io/pandocs/Audio_details.html#noise-channel-ch4
+use super::{Envelope, Length, NRX4_LENGTH_ENABLE, NRX4_TRIGGER};
+
+const NR43_SHORT_MODE: u8 = 0b0000_1000;
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub(super) struct Noise {
+    enabled: bool,
+    /// NR43: clock shift, LFSR width and clock divider.
+    polynomial: u8,
+    lfsr: u16,
+    timer: u32,
+    length: Length,
+    pub(super) envelope: Envelope,
+}
+
+impl Noise {
+    pub(super) fn new() -> Self {
+        Self {
+            enabled: false,
+            polynomial: 0,
+            lfsr: 0x7fff,
+            timer: 8,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub(super) fn volume(&self) -> u8 {
+        self.envelope.volume
+    }
+
+    /// How often the LFSR is clocked.
+    pub(super) fn frequency_hz(&self) -> f32 {
+        super::CLOCK_RATE as f32 / self.period() as f32
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        (!self.lfsr & 1) as u8 * self.envelope.volume
+    }
+
+    /// `reg` is 0-4 for the unused 0xFF1F and NR41-NR44.
+    pub(super) fn write(&mut self, reg: usize, byte: u8) {
+        match reg {
+            1 => self.length.load(byte & 0x3f),
+            2 => {
+                self.envelope.register = byte;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.polynomial = byte,
+            4 => {
+                self.length.enabled = byte & NRX4_LENGTH_ENABLE != 0;
+                if byte & NRX4_TRIGGER != 0 {
+                    self.enabled = self.dac_enabled();
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7fff;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[(self.polynomial & 0x07) as usize] << (self.polynomial >> 4)
+    }
+
+    pub(super) fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.clock_lfsr();
+        }
+        self.timer -= cycles;
+    }
+
+    /// Shifts the LFSR right, feeding bit 0 XOR bit 1 back into bit 14, and
+    /// into bit 6 as well in 7-bit mode.
+    fn clock_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.polynomial & NR43_SHORT_MODE != 0 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_lfsr_period() {
+        let mut noise = Noise::new();
+        noise.write(2, 0xf0);
+        noise.write(3, NR43_SHORT_MODE);
+        noise.write(4, NRX4_TRIGGER);
+        noise.tick(8);
+        let start = noise.lfsr & 0x7f;
+        for _ in 0..127 {
+            noise.tick(8);
+        }
+        assert_eq!(noise.lfsr & 0x7f, start);
+    }
+}
@@ -0,0 +1,241 @@
+// https://gbdev.io/pandocs/Audio_details.html#square-wave
+use super::{Envelope, Length, NRX4_LENGTH_ENABLE, NRX4_TRIGGER};
+
+/// Waveforms of the four duty cycles, one bit per step.
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+const SWEEP_NEGATE: u8 = 0b0000_1000;
+
+/// Channel 1's frequency sweep.
+struct Sweep {
+    register: u8,
+    shadow: u16,
+    timer: u8,
+    enabled: bool,
+    /// Whether a subtraction has happened since the last trigger, after which
+    /// clearing the negate bit turns the channel off.
+    negated: bool,
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0x07
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0x07
+    }
+
+    fn reload_timer(&mut self) {
+        // A period of 0 counts as 8.
+        self.timer = match self.period() {
+            0 => 8,
+            period => period,
+        };
+    }
+
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.register & SWEEP_NEGATE != 0 {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+pub(super) struct Square {
+    sweep: Option<Sweep>,
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: u32,
+    length: Length,
+    pub(super) envelope: Envelope,
+}
+
+impl Square {
+    pub(super) fn new(sweep: bool) -> Self {
+        Self {
+            sweep: sweep.then_some(Sweep {
+                register: 0,
+                shadow: 0,
+                timer: 0,
+                enabled: false,
+                negated: false,
+            }),
+            enabled: false,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 8192,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub(super) fn volume(&self) -> u8 {
+        self.envelope.volume
+    }
+
+    /// The frequency of the square wave itself, eight steps per period.
+    pub(super) fn frequency_hz(&self) -> f32 {
+        131_072.0 / (2048 - self.frequency) as f32
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let high = DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_step) & 1;
+        high * self.envelope.volume
+    }
+
+    /// `reg` is 0-4 for NRx0-NRx4.
+    pub(super) fn write(&mut self, reg: usize, byte: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    let negate_cleared = byte & SWEEP_NEGATE == 0;
+                    sweep.register = byte;
+                    if negate_cleared && sweep.negated {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = byte >> 6;
+                self.length.load(byte & 0x3f);
+            }
+            2 => {
+                self.envelope.register = byte;
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | byte as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00ff) | ((byte & 0x07) as u16) << 8;
+                self.length.enabled = byte & NRX4_LENGTH_ENABLE != 0;
+                if byte & NRX4_TRIGGER != 0 {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.frequency;
+            sweep.negated = false;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            if sweep.shift() != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 4
+    }
+
+    pub(super) fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+        self.timer -= cycles;
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub(super) fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub(super) fn clock_sweep(&mut self) {
+        let Some(sweep) = &mut self.sweep else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+
+        let frequency = sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift() != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // The new frequency is checked for overflow right away too.
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep_overflow_disables() {
+        let mut square = Square::new(true);
+        square.write(0, 0x11);
+        square.write(2, 0xf0);
+        square.write(3, 0x00);
+        square.write(4, NRX4_TRIGGER | 0x05);
+        assert!(square.enabled());
+
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x500 + 0x280);
+        assert!(!square.enabled());
+    }
+
+    #[test]
+    fn test_duty_waveform() {
+        let mut square = Square::new(false);
+        square.write(1, 0x40);
+        square.write(2, 0xf0);
+        square.write(3, 0xff);
+        square.write(4, NRX4_TRIGGER | 0x07);
+        let mut samples = Vec::new();
+        for _ in 0..8 {
+            square.tick(4);
+            samples.push(square.output());
+        }
+        assert_eq!(samples, [0, 0, 0, 0, 0, 0, 15, 15]);
+    }
+}
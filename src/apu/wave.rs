@@ -0,0 +1,111 @@
+// https://gbdev.io/pandocs/Audio_Registers.html#sound-channel-3--wave-output
+use super::{Length, NRX4_LENGTH_ENABLE, NRX4_TRIGGER};
+
+const NR30_DAC_ENABLE: u8 = 0b1000_0000;
+
+pub(super) struct Wave {
+    enabled: bool,
+    dac_enabled: bool,
+    /// Right shift applied to samples: mute, 100%, 50% and 25%.
+    volume_shift: u8,
+    frequency: u16,
+    timer: u32,
+    /// Which of the 32 4-bit samples in wave RAM is playing.
+    position: u8,
+    length: Length,
+}
+
+impl Wave {
+    pub(super) fn new() -> Self {
+        Self {
+            enabled: false,
+            dac_enabled: false,
+            volume_shift: 4,
+            frequency: 0,
+            timer: 4096,
+            position: 0,
+            length: Length::new(256),
+        }
+    }
+
+    pub(super) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub(super) fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    /// The volume shift as a level out of 15.
+    pub(super) fn volume(&self) -> u8 {
+        15 >> self.volume_shift
+    }
+
+    /// How often the whole of wave RAM is played through.
+    pub(super) fn frequency_hz(&self) -> f32 {
+        65_536.0 / (2048 - self.frequency) as f32
+    }
+
+    pub(super) fn output(&self, wave_ram: &[u8; 16]) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        let byte = wave_ram[self.position as usize / 2];
+        let sample = if self.position.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0f
+        };
+        sample >> self.volume_shift
+    }
+
+    /// `reg` is 0-4 for NR30-NR34.
+    pub(super) fn write(&mut self, reg: usize, byte: u8) {
+        match reg {
+            0 => {
+                self.dac_enabled = byte & NR30_DAC_ENABLE != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(byte),
+            2 => {
+                self.volume_shift = match (byte >> 5) & 0x03 {
+                    0 => 4,
+                    code => code - 1,
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | byte as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00ff) | ((byte & 0x07) as u16) << 8;
+                self.length.enabled = byte & NRX4_LENGTH_ENABLE != 0;
+                if byte & NRX4_TRIGGER != 0 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = self.period();
+                    self.position = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    pub(super) fn tick(&mut self, mut cycles: u32) {
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) % 32;
+        }
+        self.timer -= cycles;
+    }
+
+    pub(super) fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+}
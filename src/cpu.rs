@@ -1,38 +1,397 @@
+mod cartridge;
+mod dma;
+mod hdma;
+mod history;
 mod opcode;
 mod register;
+mod trace;
 
-use register::Registers;
+use crate::apu::Apu;
+use crate::interrupt::{IE_ADDR, IF_ADDR};
+use crate::joypad::{Buttons, Joypad};
+use crate::ppu::{Ppu, Renderer};
+use crate::serial::{Serial, SerialDevice};
+use crate::timer::Timer;
+#[cfg(test)]
+pub(crate) use cartridge::test_rom;
+use cartridge::Cartridge;
+pub(crate) use cartridge::MBC1_ROM_SIZE as MAX_ROM_IMAGE_SIZE;
+use dma::OamDma;
+use hdma::Hdma;
+pub use history::Executed;
+use history::History;
+pub use register::Registers;
+use std::io::{self, Write};
+
+// https://gbdev.io/pandocs/CGB_Registers.html#ff4d--key1-cgb-mode-only-prepare-speed-switch
+const KEY1_DOUBLE_SPEED: u8 = 0b1000_0000;
+pub(crate) const KEY1_SWITCH_ARMED: u8 = 0b0000_0001;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Model {
+    Dmg,
+    Cgb,
+}
 
 pub struct Cpu {
     reg: Registers,
+    cycles: u64,
     memory: [u8; 0xffff],
+    wram: [u8; 0x8000],
+    svbk: u8,
+    key1: u8,
+    ie: u8,
+    halted: bool,
+    stopped: bool,
+    software_breakpoints: bool,
+    breakpoint_hit: bool,
+    trace: Option<Box<dyn Write>>,
+    doctor_ly: bool,
+    history: History,
+    model: Model,
+    cartridge: Cartridge,
+    oam_dma: OamDma,
+    hdma: Hdma,
+    apu: Apu,
+    joypad: Joypad,
+    ppu: Ppu,
+    serial: Serial,
+    timer: Timer,
 }
 
 impl Cpu {
     pub fn new() -> Self {
         Self {
             reg: Registers::new(),
+            cycles: 0,
             memory: [0; 0xffff],
+            wram: [0; 0x8000],
+            svbk: 0,
+            key1: 0,
+            ie: 0,
+            halted: false,
+            stopped: false,
+            software_breakpoints: false,
+            breakpoint_hit: false,
+            trace: None,
+            doctor_ly: false,
+            history: History::new(history::DEFAULT_HISTORY_LEN),
+            model: Model::Dmg,
+            cartridge: Cartridge::new(),
+            oam_dma: OamDma::new(),
+            hdma: Hdma::new(),
+            apu: Apu::new(),
+            joypad: Joypad::new(),
+            ppu: Ppu::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
         }
     }
 
-    pub fn load_rom(&mut self, rom: Vec<u8>) {
-        if cfg!(debug_assertions) {
-            println!("ROM size: {} bytes", rom.len());
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.ppu.set_renderer(renderer);
+    }
+
+    /// Plugs `device` into the serial port, replacing whatever was there.
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial.connect(device);
+    }
+
+    /// Prints bytes sent over the serial port to stdout as they go out.
+    pub fn set_serial_echo(&mut self, echo: bool) {
+        self.serial.set_echo(echo);
+    }
+
+    /// Every byte sent over the serial port so far.
+    pub fn serial_output(&self) -> &[u8] {
+        self.serial.output()
+    }
+
+    /// RGB555 colours of the last drawn frame, row by row.
+    pub fn framebuffer(&self) -> &[u16] {
+        self.ppu.framebuffer()
+    }
+
+    /// Frames drawn since power on.
+    pub fn frames(&self) -> u64 {
+        self.ppu.frames()
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    /// For muting channels and the like while debugging audio.
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    /// Sets the rate in Hz that `read_samples` produces audio at.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.apu.set_sample_rate(rate);
+    }
+
+    /// Number of audio samples waiting to be read.
+    pub fn samples_available(&self) -> usize {
+        self.apu.samples_available()
+    }
+
+    /// Drains audio into `out` as interleaved left and right samples and
+    /// returns how many were written.
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        self.apu.read_samples(out)
+    }
+
+    /// Also produces audio for each channel alone, see
+    /// `read_channel_samples`.
+    pub fn set_channel_capture(&mut self, capture: bool) {
+        self.apu.set_channel_capture(capture);
+    }
+
+    /// Drains the audio of channel 0-3 alone, laid out like `read_samples`.
+    pub fn read_channel_samples(&mut self, channel: usize, out: &mut [f32]) -> usize {
+        self.apu.read_channel_samples(channel, out)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.apu.sample_rate()
+    }
+
+    /// Replaces the state of all buttons. Pressing a button that the game
+    /// has selected in P1 also wakes the CPU from STOP.
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.joypad.set_buttons(buttons);
+        if self.stopped && self.joypad.any_selected_pressed() {
+            self.stopped = false;
         }
+    }
 
-        for (i, byte) in rom.iter().enumerate() {
-            self.memory[i + 0x100] = *byte;
+    /// Inserts a cartridge dump, mapped from 0x0000 behind the bank
+    /// controller its header asks for. Fails if the dump isn't one that
+    /// can be run.
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> io::Result<()> {
+        log::info!(target: "mmu", "ROM size: {} bytes", rom.len());
+        self.cartridge = Cartridge::from_rom(rom)?;
+        if self.cartridge.read(cartridge::CGB_FLAG_ADDR) & 0x80 != 0 {
+            self.set_model(Model::Cgb);
         }
+        Ok(())
+    }
+
+    /// Maps `image` from 0x0000 behind MBC1 bank switching, for code that
+    /// doesn't come with a cartridge header. Anything past
+    /// `MAX_ROM_IMAGE_SIZE` can't be banked in.
+    pub(crate) fn load_rom_image(&mut self, image: Vec<u8>) {
+        self.cartridge = Cartridge::from_banked_image(image);
+    }
+
+    /// The battery-backed cartridge RAM to persist between sessions, all
+    /// banks of it. Empty if the cartridge has no battery.
+    pub fn save_ram(&self) -> &[u8] {
+        self.cartridge.save_ram()
+    }
+
+    /// Restores cartridge RAM saved with `save_ram`.
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        self.cartridge.load_save_ram(data);
+    }
+
+    pub(crate) fn set_model(&mut self, model: Model) {
+        log::info!(target: "cpu", "Running as {:?}", model);
+        self.model = model;
+        self.ppu.set_cgb_mode(model == Model::Cgb);
+        self.serial.set_cgb_mode(model == Model::Cgb);
+        // The boot ROM leaves A = 0x11 on CGB, which games use to detect it.
+        self.reg.a = if model == Model::Cgb { 0x11 } else { 0x01 };
     }
 
     pub fn run(&mut self) {
         loop {
-            let opcode = self.fetch_byte();
-            self.run_opcode(opcode);
+            self.step();
+        }
+    }
+
+    pub fn step(&mut self) {
+        // The whole system clock is stopped until a button is pressed.
+        if self.stopped {
+            return;
+        }
+
+        if self.halted {
+            self.tick(4);
+            if self.ie & self.read_u8(IF_ADDR) & 0x1f != 0 {
+                log::trace!(target: "interrupts", "Woken from HALT");
+                self.halted = false;
+            }
+            return;
+        }
+
+        self.write_trace();
+        let opcode = self.fetch_byte();
+        let cycles = self.run_opcode(opcode);
+        self.tick(cycles as u32);
+
+        // VRAM DMA keeps the CPU waiting while the rest of the hardware runs.
+        loop {
+            let stall = self.hdma.take_stall();
+            if stall == 0 {
+                break;
+            }
+            self.tick(stall);
+        }
+    }
+
+    /// Advances the rest of the hardware by `cycles` CPU cycles. In double
+    /// speed mode the timer keeps pace with the CPU while the PPU and APU
+    /// run at half of it.
+    fn tick(&mut self, cycles: u32) {
+        self.cycles += cycles as u64;
+        let dots = if self.double_speed() {
+            cycles / 2
+        } else {
+            cycles
+        };
+        self.timer.tick(cycles);
+        self.serial.tick(cycles);
+        self.tick_oam_dma(cycles);
+        for _ in 0..self.timer.take_apu_clocks() {
+            self.apu.clock_frame_sequencer();
+        }
+        self.apu.tick(dots);
+        self.ppu.tick(dots);
+        if self.ppu.take_hblank() {
+            self.hdma_hblank();
+        }
+
+        let requested = self.ppu.take_interrupts()
+            | self.timer.take_interrupts()
+            | self.serial.take_interrupts()
+            | self.joypad.take_interrupts();
+        if requested != 0 {
+            log::trace!(target: "interrupts", "Requested {:05b}", requested);
+            let flags = self.read_u8(IF_ADDR) | requested;
+            self.write_u8(IF_ADDR, flags);
+        }
+    }
+
+    /// Starts a call to the routine at `addr` with A set to `a`, as if a
+    /// CALL at `return_addr` had just run, so the routine's RET goes back
+    /// there. This is how GBS players drive init and play.
+    pub(crate) fn call(&mut self, addr: u16, return_addr: u16, a: u8) {
+        self.reg.a = a;
+        self.push_u16(return_addr);
+        self.reg.pc = addr;
+        self.halted = false;
+    }
+
+    pub(crate) fn pc(&self) -> u16 {
+        self.reg.pc
+    }
+
+    pub(crate) fn set_sp(&mut self, sp: u16) {
+        self.reg.sp = sp;
+    }
+
+    fn push_u16(&mut self, value: u16) {
+        let [high, low] = value.to_be_bytes();
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write_u8(self.reg.sp, high);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write_u8(self.reg.sp, low);
+    }
+
+    fn pop_u16(&mut self) -> u16 {
+        let low = self.read_u8(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        let high = self.read_u8(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(1);
+        u16::from_be_bytes([high, low])
+    }
+
+    /// Makes LD B,B flag a breakpoint for `take_breakpoint`, as Mooneye
+    /// test ROMs use it to signal the end of a test.
+    pub fn set_software_breakpoints(&mut self, enabled: bool) {
+        self.software_breakpoints = enabled;
+    }
+
+    /// Returns whether a breakpoint was hit since the last call.
+    pub fn take_breakpoint(&mut self) -> bool {
+        std::mem::take(&mut self.breakpoint_hit)
+    }
+
+    /// Sets how many of the last executed instructions are kept for
+    /// `recent_instructions` and crash dumps.
+    pub fn set_history_len(&mut self, len: usize) {
+        self.history.set_len(len);
+    }
+
+    /// The last instructions executed, oldest first.
+    pub fn recent_instructions(&self) -> impl Iterator<Item = &Executed> {
+        self.history.entries()
+    }
+
+    /// Prints the last instructions executed to stderr.
+    pub fn dump_history(&self) {
+        eprintln!("Last {} instructions:", self.history.entries().count());
+        for executed in self.history.entries() {
+            eprintln!("  {}", executed);
+        }
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.reg
+    }
+
+    /// CPU cycles executed since power on.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.key1 & KEY1_DOUBLE_SPEED != 0
+    }
+
+    /// Executes STOP. On CGB with a speed switch armed through KEY1 this
+    /// toggles between normal and double speed, otherwise everything stops
+    /// until a selected button is pressed.
+    pub(crate) fn stop(&mut self) {
+        self.timer.reset_div();
+        if self.model == Model::Cgb && self.key1 & KEY1_SWITCH_ARMED != 0 {
+            self.key1 = (self.key1 ^ KEY1_DOUBLE_SPEED) & !KEY1_SWITCH_ARMED;
+            self.timer.set_double_speed(self.double_speed());
+            log::debug!(target: "cpu", "Double speed: {}", self.double_speed());
+        } else if !self.joypad.any_selected_pressed() {
+            log::debug!(target: "cpu", "Stopped until a button is pressed");
+            self.stopped = true;
         }
     }
 
+    /// Index into `wram` for 0xC000-0xDFFF. 0xD000-0xDFFF maps the bank
+    /// selected by SVBK, where 0 selects bank 1 as well.
+    pub(crate) fn wram_offset(&self, addr: u16) -> usize {
+        let offset = (addr & 0x0fff) as usize;
+        if addr & 0x1000 == 0 {
+            return offset;
+        }
+        let bank = match self.svbk & 0x07 {
+            0 => 1,
+            bank => bank as usize,
+        };
+        bank * 0x1000 + offset
+    }
+
     pub(crate) fn fetch_byte(&mut self) -> u8 {
         let opcode = self.read_u8(self.reg.pc);
         self.reg.pc += 1;
@@ -40,21 +399,160 @@ impl Cpu {
     }
 
     pub(crate) fn read_u8(&mut self, addr: u16) -> u8 {
-        self.memory[addr as usize]
+        if self.oam_dma.blocks(addr) {
+            return 0xff;
+        }
+        self.bus_read(addr)
     }
 
     pub(crate) fn write_u8(&mut self, addr: u16, byte: u8) {
-        self.memory[addr as usize] = byte;
+        if self.oam_dma.blocks(addr) {
+            return;
+        }
+        self.bus_write(addr, byte);
+    }
+
+    /// Reads memory the way DMA sees it, without the CPU's access limits.
+    fn bus_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x7fff => self.cartridge.read(addr),
+            0x8000..=0x9fff => self.ppu.read_vram(addr),
+            0xa000..=0xbfff => self.cartridge.read_ram(addr),
+            0xc000..=0xfdff => self.wram[self.wram_offset(addr)],
+            0xfe00..=0xfe9f => self.ppu.read_oam(addr),
+            0xff00 => self.joypad.read(),
+            0xff01..=0xff02 => self.serial.read_register(addr),
+            0xff04..=0xff07 => self.timer.read_register(addr),
+            0xff10..=0xff3f => self.apu.read_register(addr),
+            0xff76..=0xff77 if self.model == Model::Cgb => self.apu.read_register(addr),
+            0xff46 => self.oam_dma.register(),
+            0xff44 if self.doctor_ly => trace::DOCTOR_LY,
+            0xff40..=0xff45 | 0xff47..=0xff4b | 0xff4f | 0xff68..=0xff6c => {
+                self.ppu.read_register(addr)
+            }
+            0xff4d if self.model == Model::Cgb => 0x7e | self.key1,
+            0xff55 if self.model == Model::Cgb => self.hdma.read_hdma5(),
+            0xff70 if self.model == Model::Cgb => 0xf8 | self.svbk,
+            IE_ADDR => self.ie,
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    fn bus_write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0x0000..=0x7fff => self.cartridge.write(addr, byte),
+            0x8000..=0x9fff => self.ppu.write_vram(addr, byte),
+            0xa000..=0xbfff => self.cartridge.write_ram(addr, byte),
+            0xc000..=0xfdff => self.wram[self.wram_offset(addr)] = byte,
+            0xfe00..=0xfe9f => self.ppu.write_oam(addr, byte),
+            0xff00 => self.joypad.write(byte),
+            0xff01..=0xff02 => self.serial.write_register(addr, byte),
+            0xff04..=0xff07 => self.timer.write_register(addr, byte),
+            0xff10..=0xff3f => self.apu.write_register(addr, byte),
+            0xff46 => self.start_oam_dma(byte),
+            0xff40..=0xff45 | 0xff47..=0xff4b | 0xff4f | 0xff68..=0xff6c => {
+                self.ppu.write_register(addr, byte)
+            }
+            0xff4d if self.model == Model::Cgb => {
+                self.key1 = (self.key1 & KEY1_DOUBLE_SPEED) | (byte & KEY1_SWITCH_ARMED)
+            }
+            0xff51..=0xff55 if self.model == Model::Cgb => self.write_hdma(addr, byte),
+            0xff70 if self.model == Model::Cgb => {
+                log::trace!(target: "mmu", "WRAM bank {}", byte & 0x07);
+                self.svbk = byte & 0x07
+            }
+            IE_ADDR => self.ie = byte,
+            _ => self.memory[addr as usize] = byte,
+        }
+    }
+}
+
+/// Dumps the instruction history when a panic unwinds past the CPU, which
+/// is how invalid and unimplemented opcodes end up.
+impl Drop for Cpu {
+    fn drop(&mut self) {
+        if std::thread::panicking() && !self.history.is_empty() {
+            self.dump_history();
+        }
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+
+    #[test]
+    fn test_wram_banking() {
+        let mut cpu = Cpu::new();
+        cpu.set_model(Model::Cgb);
+        for bank in 0..8 {
+            cpu.write_u8(0xff70, bank);
+            cpu.write_u8(0xd000, 0x10 + bank);
+        }
+        cpu.write_u8(0xff70, 0);
+        assert_eq!(cpu.read_u8(0xd000), 0x11);
+        cpu.write_u8(0xff70, 7);
+        assert_eq!(cpu.read_u8(0xd000), 0x17);
+        assert_eq!(cpu.read_u8(0xf000), 0x17);
+        assert_eq!(cpu.read_u8(0xff70), 0xff);
+    }
 
     #[test]
-    fn test_hello() {
-        let add = |a, b| a + b;
-        assert_eq!(2, add(1, 1));
+    fn test_speed_switch() {
+        let mut cpu = Cpu::new();
+        cpu.set_model(Model::Cgb);
+        cpu.load_rom(test_rom(&[0x10, 0x00, 0x44])).unwrap();
+        cpu.write_u8(0xff4d, KEY1_SWITCH_ARMED);
+        cpu.reg.h = 0x42;
+        cpu.step();
+        assert!(cpu.double_speed());
+        assert_eq!(cpu.read_u8(0xff4d), 0xfe);
+        assert_eq!(cpu.reg.pc, 0x0102);
+        // The padding byte is skipped rather than run as a NOP.
+        cpu.step();
+        assert_eq!(cpu.reg.b, 0x42);
+        assert_eq!(cpu.reg.pc, 0x0103);
+    }
+
+    #[test]
+    fn test_stop_until_button_press() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(test_rom(&[0x10, 0x00])).unwrap();
+        cpu.write_u8(0xff00, 0x10);
+        cpu.step();
+        assert!(cpu.stopped);
+        cpu.step();
+        assert_eq!(cpu.reg.pc, 0x0102);
+
+        cpu.set_buttons(Buttons {
+            up: true,
+            ..Buttons::default()
+        });
+        assert!(cpu.stopped);
+        cpu.set_buttons(Buttons {
+            start: true,
+            ..Buttons::default()
+        });
+        assert!(!cpu.stopped);
+    }
+
+    #[test]
+    fn test_history_includes_invalid_opcode() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(test_rom(&[0xf0, 0x44, 0xd3])).unwrap();
+        cpu.step();
+        let crash = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cpu.step()));
+        assert!(crash.is_err());
+
+        let history: Vec<&Executed> = cpu.recent_instructions().collect();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].bytes[..2], [0xf0, 0x44]);
+        assert_eq!((history[1].pc, history[1].bytes[0]), (0x0102, 0xd3));
     }
 }
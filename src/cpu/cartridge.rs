@@ -0,0 +1,311 @@
+// https://gbdev.io/pandocs/The_Cartridge_Header.html
+// https://gbdev.io/pandocs/MBC1.html
+use std::io::{self, ErrorKind};
+
+// https://gbdev.io/pandocs/The_Cartridge_Header.html#0143--cgb-flag
+pub(crate) const CGB_FLAG_ADDR: u16 = 0x143;
+// https://gbdev.io/pandocs/The_Cartridge_Header.html#0147--cartridge-type
+const CARTRIDGE_TYPE_ADDR: usize = 0x147;
+// https://gbdev.io/pandocs/The_Cartridge_Header.html#0149--ram-size
+const RAM_SIZE_ADDR: usize = 0x149;
+const HEADER_END: usize = 0x150;
+
+const BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+/// Two banks fill 0x0000-0x7FFF, all a cartridge without an MBC has.
+const PLAIN_ROM_SIZE: usize = 2 * BANK_SIZE;
+/// MBC1 selects from 128 banks.
+pub(crate) const MBC1_ROM_SIZE: usize = 128 * BANK_SIZE;
+
+/// Writing this to 0x0000-0x1FFF enables cartridge RAM, anything else
+/// disables it.
+const RAM_ENABLE: u8 = 0x0a;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Mbc {
+    None,
+    Mbc1,
+}
+
+/// The cartridge ROM at 0x0000-0x7FFF, its RAM at 0xA000-0xBFFF and the
+/// bank controller in front of both.
+pub(crate) struct Cartridge {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    mbc: Mbc,
+    /// Whether the RAM keeps its contents without power and so gets saved.
+    battery: bool,
+    ram_enabled: bool,
+    /// MBC1's BANK1 (low five bits) and BANK2 (the two above) registers.
+    bank1: u8,
+    bank2: u8,
+    /// MBC1 mode 1 applies BANK2 to 0x0000-0x3FFF and to RAM as well.
+    advanced_banking: bool,
+}
+
+impl Cartridge {
+    pub(crate) fn new() -> Self {
+        Self::from_image(Vec::new())
+    }
+
+    /// Reads the header of a cartridge dump and puts the matching bank
+    /// controller in front of it. Fails for dumps too short to have a
+    /// header, too long for their controller, or needing one that isn't
+    /// emulated.
+    pub(crate) fn from_rom(rom: Vec<u8>) -> io::Result<Self> {
+        if rom.len() < HEADER_END {
+            return Err(invalid(format!(
+                "{} bytes is too short for a cartridge header",
+                rom.len()
+            )));
+        }
+        let kind = rom[CARTRIDGE_TYPE_ADDR];
+        let (mbc, max_size, battery) = match kind {
+            0x00 | 0x08 => (Mbc::None, PLAIN_ROM_SIZE, false),
+            0x09 => (Mbc::None, PLAIN_ROM_SIZE, true),
+            0x01 | 0x02 => (Mbc::Mbc1, MBC1_ROM_SIZE, false),
+            0x03 => (Mbc::Mbc1, MBC1_ROM_SIZE, true),
+            _ => {
+                return Err(invalid(format!(
+                    "unsupported cartridge type 0x{:02X}",
+                    kind
+                )))
+            }
+        };
+        if rom.len() > max_size {
+            return Err(invalid(format!(
+                "{} bytes is too large for cartridge type 0x{:02X}",
+                rom.len(),
+                kind
+            )));
+        }
+        let ram_size = match (kind, rom[RAM_SIZE_ADDR]) {
+            // Types without RAM ignore whatever the header claims.
+            (0x00 | 0x01, _) => 0,
+            (_, 0x01) => 0x800,
+            (_, 0x02) => RAM_BANK_SIZE,
+            (_, 0x03) => 4 * RAM_BANK_SIZE,
+            (_, 0x04) => 16 * RAM_BANK_SIZE,
+            (_, 0x05) => 8 * RAM_BANK_SIZE,
+            _ => 0,
+        };
+        log::debug!(target: "mmu", "Cartridge RAM: {} bytes", ram_size);
+        Ok(Self {
+            ram: vec![0; ram_size],
+            mbc,
+            battery,
+            // Without an MBC there is nothing to gate the RAM with.
+            ram_enabled: mbc == Mbc::None,
+            ..Self::from_image(rom)
+        })
+    }
+
+    /// Maps `image` from 0x0000 behind MBC1 ROM banking with 8 KiB of RAM
+    /// enabled, for code that doesn't come with a cartridge header.
+    pub(crate) fn from_banked_image(image: Vec<u8>) -> Self {
+        Self {
+            ram: vec![0; RAM_BANK_SIZE],
+            mbc: Mbc::Mbc1,
+            ram_enabled: true,
+            ..Self::from_image(image)
+        }
+    }
+
+    fn from_image(image: Vec<u8>) -> Self {
+        Self {
+            rom: image,
+            ram: Vec::new(),
+            mbc: Mbc::None,
+            battery: false,
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            advanced_banking: false,
+        }
+    }
+
+    fn rom_banks(&self) -> usize {
+        self.rom.len().div_ceil(BANK_SIZE).max(1)
+    }
+
+    /// The bank mapped at 0x4000-0x7FFF.
+    pub(crate) fn rom_bank(&self) -> usize {
+        match self.mbc {
+            Mbc::None => 1,
+            Mbc::Mbc1 => ((self.bank2 as usize) << 5 | self.bank1 as usize) % self.rom_banks(),
+        }
+    }
+
+    /// The bank mapped at 0x0000-0x3FFF, only ever not 0 in MBC1 mode 1.
+    fn low_rom_bank(&self) -> usize {
+        if self.advanced_banking {
+            ((self.bank2 as usize) << 5) % self.rom_banks()
+        } else {
+            0
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_banking {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+
+    pub(crate) fn read(&self, addr: u16) -> u8 {
+        let index = match addr {
+            0x0000..=0x3fff => self.low_rom_bank() * BANK_SIZE + addr as usize,
+            _ => self.rom_bank() * BANK_SIZE + (addr as usize - BANK_SIZE),
+        };
+        self.rom.get(index).copied().unwrap_or(0xff)
+    }
+
+    /// Writes to ROM go to the bank controller, if there is one.
+    pub(crate) fn write(&mut self, addr: u16, byte: u8) {
+        if self.mbc == Mbc::None {
+            return;
+        }
+        match addr {
+            0x0000..=0x1fff => self.ram_enabled = byte & 0x0f == RAM_ENABLE,
+            0x2000..=0x3fff => {
+                // Bank 0 can't be selected here; asking for it gives bank 1.
+                self.bank1 = (byte & 0x1f).max(1);
+                log::trace!(target: "mmu", "ROM bank {}", self.rom_bank());
+            }
+            0x4000..=0x5fff => self.bank2 = byte & 0x03,
+            _ => self.advanced_banking = byte & 0x01 != 0,
+        }
+    }
+
+    /// Index into `ram` for 0xA000-0xBFFF, or `None` while it's disabled or
+    /// there is none. Smaller RAM repeats over the whole range.
+    fn ram_index(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let offset = self.ram_bank() * RAM_BANK_SIZE + (addr as usize - 0xa000);
+        Some(offset % self.ram.len())
+    }
+
+    pub(crate) fn read_ram(&self, addr: u16) -> u8 {
+        self.ram_index(addr).map_or(0xff, |index| self.ram[index])
+    }
+
+    pub(crate) fn write_ram(&mut self, addr: u16, byte: u8) {
+        if let Some(index) = self.ram_index(addr) {
+            self.ram[index] = byte;
+        }
+    }
+
+    /// All banks of battery-backed RAM, empty if the cartridge has none.
+    pub(crate) fn save_ram(&self) -> &[u8] {
+        if self.battery {
+            &self.ram
+        } else {
+            &[]
+        }
+    }
+
+    pub(crate) fn load_save_ram(&mut self, data: &[u8]) {
+        if !self.battery {
+            return;
+        }
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+/// A 32 KiB cartridge without an MBC that runs `code` from the entry point
+/// at 0x100, with its header checksum filled in.
+#[cfg(test)]
+pub(crate) fn test_rom(code: &[u8]) -> Vec<u8> {
+    let mut rom = vec![0; PLAIN_ROM_SIZE];
+    rom[0x100..0x100 + code.len()].copy_from_slice(code);
+    rom[0x14d] = rom[0x134..0x14d]
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1));
+    rom
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejects_bad_dumps() {
+        assert!(Cartridge::from_rom(vec![0; 0x100]).is_err());
+        assert!(Cartridge::from_rom(vec![0; PLAIN_ROM_SIZE * 2]).is_err());
+
+        let mut rom = test_rom(&[]);
+        rom[CARTRIDGE_TYPE_ADDR] = 0x1b;
+        assert!(Cartridge::from_rom(rom).is_err());
+    }
+
+    #[test]
+    fn test_mbc1_rom_banking() {
+        let mut rom = vec![0; 4 * BANK_SIZE];
+        rom[CARTRIDGE_TYPE_ADDR] = 0x01;
+        for bank in 0..4 {
+            rom[bank * BANK_SIZE + 0x10] = bank as u8;
+        }
+        let mut cartridge = Cartridge::from_rom(rom).unwrap();
+        assert_eq!(cartridge.read(0x0010), 0);
+        assert_eq!(cartridge.read(0x4010), 1);
+
+        cartridge.write(0x2000, 3);
+        assert_eq!(cartridge.read(0x4010), 3);
+        assert_eq!(cartridge.read(0x0010), 0);
+        cartridge.write(0x2000, 0);
+        assert_eq!(cartridge.read(0x4010), 1);
+        // Banks past the end of the ROM wrap around.
+        cartridge.write(0x2000, 6);
+        assert_eq!(cartridge.read(0x4010), 2);
+    }
+
+    #[test]
+    fn test_mbc1_ram_banking() {
+        let mut rom = test_rom(&[]);
+        rom[CARTRIDGE_TYPE_ADDR] = 0x03;
+        rom[RAM_SIZE_ADDR] = 0x03;
+        let mut cartridge = Cartridge::from_rom(rom).unwrap();
+        cartridge.write_ram(0xa000, 0x11);
+        assert_eq!(cartridge.read_ram(0xa000), 0xff);
+
+        cartridge.write(0x0000, RAM_ENABLE);
+        cartridge.write(0x6000, 0x01);
+        for bank in 0..4 {
+            cartridge.write(0x4000, bank);
+            cartridge.write_ram(0xbfff, 0x10 + bank);
+        }
+        cartridge.write(0x4000, 2);
+        assert_eq!(cartridge.read_ram(0xbfff), 0x12);
+        let save = cartridge.save_ram();
+        assert_eq!(save.len(), 4 * RAM_BANK_SIZE);
+        assert_eq!(save[3 * RAM_BANK_SIZE + 0x1fff], 0x13);
+
+        cartridge.write(0x0000, 0x00);
+        assert_eq!(cartridge.read_ram(0xbfff), 0xff);
+    }
+
+    #[test]
+    fn test_save_ram_needs_battery() {
+        let mut rom = test_rom(&[]);
+        rom[CARTRIDGE_TYPE_ADDR] = 0x08;
+        rom[RAM_SIZE_ADDR] = 0x02;
+        let mut cartridge = Cartridge::from_rom(rom.clone()).unwrap();
+        cartridge.write_ram(0xa000, 0x42);
+        assert_eq!(cartridge.read_ram(0xa000), 0x42);
+        assert!(cartridge.save_ram().is_empty());
+
+        rom[CARTRIDGE_TYPE_ADDR] = 0x09;
+        let mut cartridge = Cartridge::from_rom(rom).unwrap();
+        cartridge.load_save_ram(&[0x42]);
+        assert_eq!(cartridge.read_ram(0xa000), 0x42);
+        assert_eq!(cartridge.save_ram().len(), RAM_BANK_SIZE);
+    }
+}
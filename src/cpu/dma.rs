@@ -0,0 +1,140 @@
+// https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+use super::Cpu;
+
+const OAM_DMA_LENGTH: u8 = 0xa0;
+const CYCLES_PER_BYTE: u32 = 4;
+
+pub(crate) struct OamDma {
+    register: u8,
+    /// Source address and number of bytes copied so far.
+    active: Option<(u16, u8)>,
+    /// A transfer that was requested and is still in its start-up cycle.
+    starting: Option<u16>,
+    cycles: u32,
+}
+
+impl OamDma {
+    pub(crate) fn new() -> Self {
+        Self {
+            register: 0xff,
+            active: None,
+            starting: None,
+            cycles: 0,
+        }
+    }
+
+    pub(crate) fn register(&self) -> u8 {
+        self.register
+    }
+
+    /// While a transfer runs the CPU only reaches HRAM. IE sits next to it,
+    /// and DMA itself stays writable so a transfer can be restarted from
+    /// code running in HRAM.
+    pub(crate) fn blocks(&self, addr: u16) -> bool {
+        self.active.is_some() && !matches!(addr, 0xff80..=0xffff | 0xff46)
+    }
+}
+
+impl Cpu {
+    pub(crate) fn start_oam_dma(&mut self, byte: u8) {
+        self.oam_dma.register = byte;
+        // 0xE000-0xFFFF are read through the WRAM echo instead of OAM/IO.
+        let source = match byte {
+            0xe0..=0xff => (byte as u16 - 0x20) << 8,
+            _ => (byte as u16) << 8,
+        };
+        log::debug!(target: "mmu", "OAM DMA from {:04X}", source);
+        // A running transfer keeps going until the new one has started.
+        self.oam_dma.starting = Some(source);
+        if self.oam_dma.active.is_none() {
+            self.oam_dma.cycles = 0;
+        }
+    }
+
+    pub(crate) fn tick_oam_dma(&mut self, cycles: u32) {
+        if self.oam_dma.active.is_none() && self.oam_dma.starting.is_none() {
+            return;
+        }
+        self.oam_dma.cycles += cycles;
+        while self.oam_dma.cycles >= CYCLES_PER_BYTE {
+            self.oam_dma.cycles -= CYCLES_PER_BYTE;
+
+            if let Some((source, index)) = self.oam_dma.active {
+                let byte = self.bus_read(source + index as u16);
+                self.ppu.write_oam(0xfe00 + index as u16, byte);
+                self.oam_dma.active = if index + 1 < OAM_DMA_LENGTH {
+                    Some((source, index + 1))
+                } else {
+                    None
+                };
+            }
+
+            if let Some(source) = self.oam_dma.starting.take() {
+                self.oam_dma.active = Some((source, 0));
+            }
+
+            if self.oam_dma.active.is_none() {
+                self.oam_dma.cycles = 0;
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_oam_dma_copy() {
+        let mut cpu = Cpu::new();
+        for i in 0..OAM_DMA_LENGTH as u16 {
+            cpu.write_u8(0xc100 + i, i as u8);
+        }
+        cpu.write_u8(0xff46, 0xc1);
+        cpu.tick(4);
+        assert_eq!(cpu.read_u8(0xc100), 0xff);
+        assert_eq!(cpu.read_u8(0xff46), 0xc1);
+
+        cpu.write_u8(0xff80, 0x80);
+        assert_eq!(cpu.read_u8(0xff80), 0x80);
+        assert_eq!(cpu.read_u8(0xff40), 0xff);
+
+        cpu.tick(640);
+        assert_eq!(cpu.read_u8(0xc105), 0x05);
+        assert_eq!(cpu.read_u8(0xfe9f), 0x9f);
+    }
+
+    #[test]
+    fn test_oam_dma_echo_source() {
+        let mut cpu = Cpu::new();
+        cpu.write_u8(0xde00, 0x42);
+        cpu.write_u8(0xff46, 0xfe);
+        cpu.tick(644);
+        assert_eq!(cpu.read_u8(0xfe00), 0x42);
+    }
+
+    #[test]
+    fn test_oam_dma_restart() {
+        let mut cpu = Cpu::new();
+        for i in 0..OAM_DMA_LENGTH as u16 {
+            cpu.write_u8(0xc000 + i, 0x11);
+            cpu.write_u8(0xd000 + i, i as u8);
+        }
+        cpu.write_u8(0xff46, 0xc0);
+        cpu.tick(320);
+        cpu.write_u8(0xff46, 0xd0);
+        cpu.tick(4);
+        assert_eq!(cpu.read_u8(0xfe00), 0xff);
+        // The first transfer got halfway before the second took over.
+        assert_eq!(cpu.ppu.read_oam(0xfe4f), 0x11);
+        assert_eq!(cpu.ppu.read_oam(0xfe50), 0x00);
+
+        cpu.tick(636);
+        assert_eq!(cpu.read_u8(0xfe00), 0xff);
+        cpu.tick(4);
+        for i in 0..OAM_DMA_LENGTH as u16 {
+            assert_eq!(cpu.read_u8(0xfe00 + i), i as u8);
+        }
+    }
+}
@@ -0,0 +1,183 @@
+// https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+use super::Cpu;
+
+const BLOCK_LENGTH: u16 = 0x10;
+const HDMA5_HBLANK: u8 = 0b1000_0000;
+/// The CPU is paused for 32 dots per block, which is twice as many CPU
+/// cycles in double speed mode.
+const BLOCK_DOTS: u32 = 32;
+
+pub(crate) struct Hdma {
+    source: u16,
+    dest: u16,
+    /// Blocks of 16 bytes left to copy.
+    remaining: u8,
+    hblank: bool,
+    stall: u32,
+}
+
+impl Hdma {
+    pub(crate) fn new() -> Self {
+        Self {
+            source: 0,
+            dest: 0,
+            remaining: 0,
+            hblank: false,
+            stall: 0,
+        }
+    }
+
+    /// HDMA5 reads back the blocks left minus one, with bit 7 clear while an
+    /// HBlank transfer is running. A finished transfer reads 0xFF.
+    pub(crate) fn read_hdma5(&self) -> u8 {
+        let active = if self.hblank { 0 } else { HDMA5_HBLANK };
+        active | (self.remaining.wrapping_sub(1) & 0x7f)
+    }
+
+    /// Returns and clears the cycles the CPU has to sit out for blocks
+    /// copied since the last call.
+    pub(crate) fn take_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.stall)
+    }
+}
+
+impl Cpu {
+    pub(crate) fn write_hdma(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0xff51 => self.hdma.source = (self.hdma.source & 0x00f0) | ((byte as u16) << 8),
+            0xff52 => self.hdma.source = (self.hdma.source & 0xff00) | (byte as u16 & 0xf0),
+            0xff53 => self.hdma.dest = (self.hdma.dest & 0x00f0) | ((byte as u16 & 0x1f) << 8),
+            0xff54 => self.hdma.dest = (self.hdma.dest & 0x1f00) | (byte as u16 & 0xf0),
+            0xff55 => {
+                // Writing with bit 7 clear during an HBlank transfer stops it
+                // instead of starting a general purpose one.
+                if self.hdma.hblank && byte & HDMA5_HBLANK == 0 {
+                    self.hdma.hblank = false;
+                    return;
+                }
+
+                self.hdma.remaining = (byte & 0x7f) + 1;
+                log::debug!(
+                    target: "mmu",
+                    "VRAM DMA of {} blocks from {:04X} to {:04X}",
+                    self.hdma.remaining,
+                    self.hdma.source,
+                    self.hdma.dest
+                );
+                if byte & HDMA5_HBLANK != 0 {
+                    self.hdma.hblank = true;
+                    // With the LCD off there is no HBlank to wait for, and
+                    // the first block goes out right away.
+                    if !self.ppu.lcd_enabled() {
+                        self.hdma_block();
+                    }
+                } else {
+                    while self.hdma.remaining > 0 {
+                        self.hdma_block();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Called whenever the PPU enters HBlank on a visible line.
+    pub(crate) fn hdma_hblank(&mut self) {
+        if self.hdma.hblank {
+            self.hdma_block();
+        }
+    }
+
+    fn hdma_block(&mut self) {
+        for _ in 0..BLOCK_LENGTH {
+            let byte = self.bus_read(self.hdma.source);
+            self.ppu.write_vram(0x8000 | self.hdma.dest, byte);
+            self.hdma.source = self.hdma.source.wrapping_add(1);
+            self.hdma.dest = (self.hdma.dest + 1) & 0x1fff;
+        }
+
+        self.hdma.remaining -= 1;
+        if self.hdma.remaining == 0 {
+            self.hdma.hblank = false;
+        }
+
+        // A halted CPU has nothing to pause.
+        if !self.halted {
+            let speed = if self.double_speed() { 2 } else { 1 };
+            self.hdma.stall += BLOCK_DOTS * speed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{test_rom, Model};
+
+    fn cgb_with_source() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_model(Model::Cgb);
+        for i in 0..0x80 {
+            cpu.write_u8(0xc000 + i, i as u8 + 1);
+        }
+        cpu.write_u8(0xff51, 0xc0);
+        cpu.write_u8(0xff52, 0x00);
+        cpu.write_u8(0xff53, 0x01);
+        cpu.write_u8(0xff54, 0x00);
+        cpu
+    }
+
+    #[test]
+    fn test_general_purpose_dma() {
+        let mut cpu = cgb_with_source();
+        cpu.write_u8(0xff55, 0x03);
+        assert_eq!(cpu.read_u8(0x8100), 0x01);
+        assert_eq!(cpu.read_u8(0x813f), 0x40);
+        assert_eq!(cpu.read_u8(0x8140), 0x00);
+        assert_eq!(cpu.read_u8(0xff55), 0xff);
+        assert_eq!(cpu.hdma.take_stall(), 4 * BLOCK_DOTS);
+    }
+
+    #[test]
+    fn test_hblank_dma_and_cancel() {
+        let mut cpu = cgb_with_source();
+        cpu.write_u8(0xff55, HDMA5_HBLANK | 0x03);
+        assert_eq!(cpu.read_u8(0xff55), 0x03);
+        assert_eq!(cpu.read_u8(0x8100), 0x00);
+
+        // OAM scan and drawing of line 0 end 252 dots in.
+        cpu.tick(252);
+        assert_eq!(cpu.read_u8(0x810f), 0x10);
+        assert_eq!(cpu.read_u8(0x8110), 0x00);
+        assert_eq!(cpu.read_u8(0xff55), 0x02);
+
+        cpu.write_u8(0xff55, 0x00);
+        assert_eq!(cpu.read_u8(0xff55), 0x82);
+        cpu.tick(456);
+        assert_eq!(cpu.read_u8(0x8110), 0x00);
+    }
+
+    #[test]
+    fn test_hblank_dma_lcd_off() {
+        let mut cpu = cgb_with_source();
+        cpu.write_u8(0xff40, 0x00);
+        cpu.write_u8(0xff55, HDMA5_HBLANK | 0x01);
+        assert_eq!(cpu.read_u8(0x810f), 0x10);
+        assert_eq!(cpu.read_u8(0xff55), 0x00);
+    }
+
+    #[test]
+    fn test_hblank_dma_while_halted() {
+        let mut cpu = cgb_with_source();
+        cpu.write_u8(0xff55, HDMA5_HBLANK | 0x01);
+        cpu.load_rom(test_rom(&[0x76])).unwrap();
+        cpu.write_u8(0xffff, 0x00);
+        cpu.step();
+        while cpu.read_u8(0xff55) != 0xff {
+            cpu.step();
+        }
+        assert!(cpu.halted);
+        assert_eq!(cpu.read_u8(0x811f), 0x20);
+        assert_eq!(cpu.hdma.take_stall(), 0);
+    }
+}
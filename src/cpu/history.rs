@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use super::opcode::OPCODE_DATA;
+use super::register::Registers;
+
+/// Instructions kept by default, enough to see how the CPU got somewhere.
+pub(crate) const DEFAULT_HISTORY_LEN: usize = 32;
+
+/// One executed instruction with the registers as they were before it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Executed {
+    pub pc: u16,
+    /// The bank mapped where `pc` points: the switchable ROM bank, which
+    /// is always 1 without a memory bank controller, or the WRAM bank.
+    pub bank: u8,
+    pub bytes: [u8; 3],
+    pub len: u8,
+    pub registers: Registers,
+}
+
+impl fmt::Display for Executed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes[..self.len as usize]
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let name = OPCODE_DATA
+            .get(&self.bytes[0])
+            .map_or("?", |op| op.name.as_str());
+        let reg = &self.registers;
+        write!(
+            f,
+            "{:02X}:{:04X}  {:8} {:10} af={:04X} bc={:04X} de={:04X} hl={:04X} sp={:04X}",
+            self.bank,
+            self.pc,
+            bytes.join(" "),
+            name,
+            reg.af(),
+            reg.bc(),
+            reg.de(),
+            reg.hl(),
+            reg.sp
+        )
+    }
+}
+
+/// A ring buffer of the last instructions executed, for working out what
+/// led up to a crash.
+pub(crate) struct History {
+    entries: VecDeque<Executed>,
+    len: usize,
+}
+
+impl History {
+    pub(crate) fn new(len: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(len),
+            len,
+        }
+    }
+
+    pub(crate) fn set_len(&mut self, len: usize) {
+        self.len = len;
+        while self.entries.len() > len {
+            self.entries.pop_front();
+        }
+    }
+
+    pub(crate) fn push(&mut self, executed: Executed) {
+        if self.len == 0 {
+            return;
+        }
+        if self.entries.len() == self.len {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(executed);
+    }
+
+    /// Oldest first.
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &Executed> {
+        self.entries.iter()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer_keeps_latest() {
+        let mut history = History::new(2);
+        for pc in 0x100..0x104 {
+            history.push(Executed {
+                pc,
+                bank: 0,
+                bytes: [0x44, 0, 0],
+                len: 1,
+                registers: Registers::new(),
+            });
+        }
+        let pcs: Vec<u16> = history.entries().map(|executed| executed.pc).collect();
+        assert_eq!(pcs, [0x102, 0x103]);
+        assert_eq!(
+            history.entries().last().unwrap().to_string(),
+            "00:0103  44       LD B, H    af=01B0 bc=0013 de=00D8 hl=014D sp=FFFE"
+        );
+
+        history.set_len(1);
+        assert_eq!(history.entries().count(), 1);
+    }
+
+    #[test]
+    fn test_display_parses_as_trace() {
+        let executed = Executed {
+            pc: 0x4abc,
+            bank: 3,
+            bytes: [0xc3, 0x50, 0x01],
+            len: 3,
+            registers: Registers::new(),
+        };
+        let state = crate::trace_diff::TraceState::parse(&executed.to_string()).unwrap();
+        assert_eq!(state.pc, 0x4abc);
+        assert_eq!((state.a, state.f, state.sp), (0x01, 0xb0, 0xfffe));
+    }
+}
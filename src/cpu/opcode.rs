@@ -1,7 +1,7 @@
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
 
-use super::Cpu;
+use super::{Cpu, Executed};
 use crate::cpu::register::{CARRY_FLAG, HALF_CARRY_FLAG, SUBTRACT_FLAG, ZERO_FLAG};
 
 pub struct Opcode {
@@ -55,7 +55,7 @@ pub static OPCODE_DATA: Lazy<HashMap<u8, Opcode>> = Lazy::new(|| {
     map.insert(0x0D, Opcode::new(OpType::Alu,      1, 4,  "DEC C"));
     map.insert(0x0E, Opcode::new(OpType::Load,     2, 8,  "LD C, n"));
     map.insert(0x0F, Opcode::new(OpType::RotShift, 1, 4,  "RRCA"));
-    map.insert(0x10, Opcode::new(OpType::Misc,     1, 4,  "STOP"));
+    map.insert(0x10, Opcode::new(OpType::Misc,     2, 4,  "STOP"));
     map.insert(0x11, Opcode::new(OpType::Load,     3, 12, "LD DE, nn"));
     map.insert(0x12, Opcode::new(OpType::Load,     1, 8,  "LD (DE), A"));
     map.insert(0x13, Opcode::new(OpType::Misc,     1, 8,  "INC DE"));
@@ -299,8 +299,10 @@ pub static OPCODE_DATA: Lazy<HashMap<u8, Opcode>> = Lazy::new(|| {
     map
 });
 
+#[allow(clippy::match_single_binding)]
 impl Cpu {
-    pub(crate) fn run_opcode(&mut self, opcode: u8) {
+    /// Executes `opcode` and returns the number of cycles it took.
+    pub(crate) fn run_opcode(&mut self, opcode: u8) -> u8 {
         let op = OPCODE_DATA.get(&opcode).unwrap();
         self.op_log(opcode, op);
 
@@ -315,36 +317,36 @@ impl Cpu {
             OpType::Return => self.op_return(opcode, op),
             OpType::Invalid => panic!("Invalid opcode: 0x{:02X}", opcode),
         };
+        op.cycles
     }
 
+    /// Records the instruction about to run in the history, and the `cpu`
+    /// trace log, with the registers as they are before it.
     fn op_log(&mut self, opcode: u8, op: &Opcode) {
-        let bytes = match op.bytes {
-            1 => format!("{:02X}", opcode),
-            2 => format!("{:02X} {:02X}", opcode, self.read_u8(self.reg.pc)),
-            3 => format!(
-                "{:02X} {:02X} {:02X}",
-                opcode,
-                self.read_u8(self.reg.pc),
-                self.read_u8(self.reg.pc + 1)
-            ),
-            _ => panic!("Invalid number of bytes for opcode: 0x{:02X}", opcode),
+        let pc = self.reg.pc.wrapping_sub(1);
+        let mut bytes = [opcode, 0, 0];
+        for (i, byte) in bytes.iter_mut().enumerate().take(op.bytes as usize).skip(1) {
+            *byte = self.read_u8(pc.wrapping_add(i as u16));
+        }
+        let bank = match pc {
+            0x4000..=0x7fff => self.cartridge.rom_bank() as u8,
+            0xd000..=0xdfff => (self.wram_offset(pc) / 0x1000) as u8,
+            _ => 0,
         };
-
-        println!(
-            "{:8} {:10} af={:04X} bc={:04X} dw={:04X} hl={:04X} sp={:04X} pc={:04X}",
+        let executed = Executed {
+            pc,
+            bank,
             bytes,
-            op.name,
-            self.reg.af(),
-            self.reg.bc(),
-            self.reg.de(),
-            self.reg.hl(),
-            self.reg.sp,
-            self.reg.pc - 0x101
-        );
+            len: op.bytes,
+            registers: self.reg,
+        };
+        log::trace!(target: "cpu", "{}", executed);
+        self.history.push(executed);
     }
 
     fn op_load(&mut self, opcode: u8, op: &Opcode) {
         match opcode {
+            0x40 => self.breakpoint_hit = self.software_breakpoints,
             0x44 => self.reg.b = self.reg.h,
             0xF0 => {
                 let n = self.read_u8(self.reg.pc);
@@ -368,36 +370,41 @@ impl Cpu {
 
     fn op_misc(&mut self, opcode: u8, op: &Opcode) {
         match opcode {
+            // STOP skips the byte after it, which is normally 0x00.
+            0x10 => self.stop(),
+            0x76 => self.halted = true,
             _ => panic!("Unknown opcode: 0x{:02X}", opcode),
         }
+        self.reg.pc += (op.bytes - 1) as u16;
     }
 
-    fn op_rot_shift(&mut self, opcode: u8, op: &Opcode) {
+    fn op_rot_shift(&mut self, opcode: u8, _op: &Opcode) {
         match opcode {
             _ => panic!("Unknown opcode: 0x{:02X}", opcode),
         }
     }
 
-    fn op_bit(&mut self, opcode: u8, op: &Opcode) {
+    fn op_bit(&mut self, opcode: u8, _op: &Opcode) {
         match opcode {
             _ => panic!("Unknown opcode: 0x{:02X}", opcode),
         }
     }
 
-    fn op_jump(&mut self, opcode: u8, op: &Opcode) {
+    fn op_jump(&mut self, opcode: u8, _op: &Opcode) {
         match opcode {
             _ => panic!("Unknown opcode: 0x{:02X}", opcode),
         }
     }
 
-    fn op_call(&mut self, opcode: u8, op: &Opcode) {
+    fn op_call(&mut self, opcode: u8, _op: &Opcode) {
         match opcode {
             _ => panic!("Unknown opcode: 0x{:02X}", opcode),
         }
     }
 
-    fn op_return(&mut self, opcode: u8, op: &Opcode) {
+    fn op_return(&mut self, opcode: u8, _op: &Opcode) {
         match opcode {
+            0xC9 => self.reg.pc = self.pop_u16(),
             _ => panic!("Unknown opcode: 0x{:02X}", opcode),
         }
     }
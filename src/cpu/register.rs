@@ -1,4 +1,5 @@
 // https://gbdev.io/pandocs/CPU_Registers_and_Flags.html
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Registers {
     pub a: u8,
     pub f: u8,
@@ -57,3 +58,9 @@ impl Registers {
         }
     }
 }
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
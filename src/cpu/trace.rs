@@ -0,0 +1,77 @@
+// https://github.com/robert/gameboy-doctor#generating-a-log-file
+use std::io::Write;
+
+use super::Cpu;
+
+/// What LY reads as while tracing: Gameboy Doctor's logs come from
+/// emulators with the LCD stuck in VBlank so they don't depend on timing.
+pub(crate) const DOCTOR_LY: u8 = 0x90;
+
+impl Cpu {
+    /// Writes a Gameboy Doctor line before every instruction to `trace`,
+    /// and has LY read as 0x90 the way the reference logs expect. `None`
+    /// turns it back off.
+    pub fn set_doctor_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.doctor_ly = trace.is_some();
+        self.trace = trace;
+    }
+
+    /// Has LY read as 0x90 without writing a trace, for comparing against
+    /// Gameboy Doctor logs some other way.
+    pub fn set_doctor_ly(&mut self, enabled: bool) {
+        self.doctor_ly = enabled;
+    }
+
+    pub(super) fn write_trace(&mut self) {
+        if self.trace.is_none() {
+            return;
+        }
+        let line = self.doctor_line();
+        if let Some(trace) = &mut self.trace {
+            writeln!(trace, "{}", line).expect("Failed to write trace");
+        }
+    }
+
+    /// The registers and the four bytes at PC, as
+    /// `A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02`.
+    pub fn doctor_line(&mut self) -> String {
+        let pc = self.reg.pc;
+        let pcmem: Vec<String> = (0..4)
+            .map(|i| format!("{:02X}", self.bus_read(pc.wrapping_add(i))))
+            .collect();
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+            self.reg.a,
+            self.reg.f,
+            self.reg.b,
+            self.reg.c,
+            self.reg.d,
+            self.reg.e,
+            self.reg.h,
+            self.reg.l,
+            self.reg.sp,
+            pc,
+            pcmem.join(",")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::test_rom;
+
+    #[test]
+    fn test_doctor_line() {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(test_rom(&[0x00, 0xc3, 0x13, 0x02])).unwrap();
+        assert_eq!(
+            cpu.doctor_line(),
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02"
+        );
+
+        assert_ne!(cpu.read_u8(0xff44), DOCTOR_LY);
+        cpu.set_doctor_trace(Some(Box::new(std::io::sink())));
+        assert_eq!(cpu.read_u8(0xff44), DOCTOR_LY);
+    }
+}
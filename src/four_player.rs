@@ -0,0 +1,357 @@
+// https://gbdev.io/pandocs/Four_Player_Adapter.html
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::cpu::Cpu;
+use crate::serial::SerialDevice;
+
+pub const MAX_PLAYERS: usize = 4;
+
+const PING_ID: u8 = 0xfe;
+const PING_ACK: u8 = 0x88;
+const PING_END: u8 = 0xaa;
+const PING_PACKET_LEN: usize = 4;
+const TRANSMISSION_START: u8 = 0xcc;
+const TRANSMISSION_RESET: u8 = 0xff;
+
+/// CPU cycles to shift one byte at the 8192 Hz serial clock.
+const CYCLES_PER_BYTE: u64 = 8 * 512;
+/// The gap the adapter leaves after each byte, lengthened by the low nibble
+/// of RATE.
+const MIN_GAP_CYCLES: u64 = 0x510;
+const GAP_CYCLES_PER_RATE: u64 = 196;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Phase {
+    Ping,
+    Starting,
+    Transmission,
+}
+
+/// The adapter's side of the protocol. Every call to `clock` is one byte
+/// shifted to and from all four ports at once.
+struct Dmg07 {
+    phase: Phase,
+    /// Byte within the current ping packet or transmission round.
+    index: usize,
+    /// Bit n set when player n + 1 answered the last ping packet.
+    connected: u8,
+    acked: u8,
+    ending: bool,
+    rate: u8,
+    size: usize,
+    /// The packets of all players from the previous round, sent back out.
+    buffer: Vec<u8>,
+    next: Vec<u8>,
+}
+
+impl Dmg07 {
+    fn new() -> Self {
+        Self {
+            phase: Phase::Ping,
+            index: 0,
+            connected: 0,
+            acked: 0,
+            ending: true,
+            rate: 0,
+            size: 1,
+            buffer: Vec::new(),
+            next: Vec::new(),
+        }
+    }
+
+    /// `sent` holds what each player shifted out, `None` when it had no
+    /// transfer armed. Returns what the adapter shifts into each player.
+    fn clock(&mut self, sent: [Option<u8>; MAX_PLAYERS]) -> [u8; MAX_PLAYERS] {
+        match self.phase {
+            Phase::Ping => self.ping(sent),
+            Phase::Starting => {
+                self.index += 1;
+                if self.index == PING_PACKET_LEN {
+                    self.start_transmission();
+                }
+                [TRANSMISSION_START; MAX_PLAYERS]
+            }
+            Phase::Transmission => self.transmit(sent),
+        }
+    }
+
+    /// Ping packets are the ID byte followed by three copies of a status
+    /// byte: the player's number in bits 0-2 and which players are connected
+    /// in bits 4-7. Game Boys answer 0x88 twice, then RATE and SIZE.
+    fn ping(&mut self, sent: [Option<u8>; MAX_PLAYERS]) -> [u8; MAX_PLAYERS] {
+        let mut replies = [PING_ID; MAX_PLAYERS];
+        if self.index > 0 {
+            for (player, reply) in replies.iter_mut().enumerate() {
+                *reply = (self.connected << 4) | (player as u8 + 1);
+            }
+        }
+
+        for (player, byte) in sent.iter().enumerate() {
+            match self.index {
+                0 if *byte == Some(PING_ACK) => self.acked |= 1 << player,
+                1 if *byte != Some(PING_ACK) => self.acked &= !(1 << player),
+                _ => {}
+            }
+        }
+        // Player 1 decides the packet timing and size for everyone.
+        match (self.index, sent[0]) {
+            (2, Some(rate)) => self.rate = rate,
+            (3, Some(size)) if self.acked & 1 != 0 => self.size = (size as usize).clamp(1, 4),
+            _ => {}
+        }
+        self.ending &= sent[0] == Some(PING_END);
+
+        self.index += 1;
+        if self.index == PING_PACKET_LEN {
+            self.index = 0;
+            if self.ending {
+                self.phase = Phase::Starting;
+            } else {
+                self.connected = std::mem::take(&mut self.acked);
+            }
+            self.ending = true;
+        }
+        replies
+    }
+
+    /// CPU cycles from one byte to the next at the current RATE.
+    fn byte_period(&self) -> u64 {
+        CYCLES_PER_BYTE + MIN_GAP_CYCLES + (self.rate & 0x0f) as u64 * GAP_CYCLES_PER_RATE
+    }
+
+    fn start_transmission(&mut self) {
+        self.phase = Phase::Transmission;
+        self.index = 0;
+        self.buffer = vec![0x00; self.size * MAX_PLAYERS];
+        self.next = vec![TRANSMISSION_RESET; self.size * MAX_PLAYERS];
+    }
+
+    /// A round is SIZE bytes per player. Every player sends its packet in the
+    /// first SIZE bytes, while all of them receive the previous round's
+    /// packets, player 1's first.
+    fn transmit(&mut self, sent: [Option<u8>; MAX_PLAYERS]) -> [u8; MAX_PLAYERS] {
+        let reply = self.buffer[self.index];
+        if self.index < self.size {
+            for (player, byte) in sent.iter().enumerate() {
+                if self.connected & (1 << player) != 0 {
+                    self.next[player * self.size + self.index] = byte.unwrap_or(0xff);
+                }
+            }
+        }
+
+        self.index += 1;
+        if self.index == self.buffer.len() {
+            self.index = 0;
+            // A packet of nothing but 0xFF from player 1 restarts the pings.
+            if self.next[..self.size]
+                .iter()
+                .all(|&b| b == TRANSMISSION_RESET)
+            {
+                *self = Self::new();
+            } else {
+                self.buffer = std::mem::replace(
+                    &mut self.next,
+                    vec![TRANSMISSION_RESET; self.size * MAX_PLAYERS],
+                );
+            }
+        }
+        [reply; MAX_PLAYERS]
+    }
+}
+
+/// What every port has armed on the external clock and the byte the adapter
+/// clocked into it, like the wire of a `LinkCable`.
+#[derive(Default)]
+struct Ports {
+    waiting: [Option<u8>; MAX_PLAYERS],
+    inbox: [Option<u8>; MAX_PLAYERS],
+}
+
+/// One of the adapter's four plugs.
+struct AdapterPort {
+    ports: Rc<RefCell<Ports>>,
+    player: usize,
+}
+
+impl SerialDevice for AdapterPort {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        // The adapter drives the clock, it never listens to one.
+        0xff
+    }
+
+    fn poll(&mut self, waiting: Option<u8>) -> Option<u8> {
+        let mut ports = self.ports.borrow_mut();
+        if let Some(received) = ports.inbox[self.player].take() {
+            return Some(received);
+        }
+        ports.waiting[self.player] = waiting;
+        None
+    }
+}
+
+/// Up to four emulator instances in one process plugged into a DMG-07.
+///
+/// The adapter is the clock master: once per byte period, as set by player
+/// 1's RATE, it exchanges one byte with each Game Boy that has a transfer
+/// armed on the external clock.
+/// As with `LinkCable`, `step` advances whichever instance is furthest
+/// behind.
+pub struct FourPlayerAdapter {
+    cpus: Vec<Cpu>,
+    ports: Rc<RefCell<Ports>>,
+    dmg07: Dmg07,
+    next_byte: u64,
+}
+
+impl FourPlayerAdapter {
+    /// Plugs `cpus` in, the first one as player 1.
+    pub fn new(mut cpus: Vec<Cpu>) -> Self {
+        assert!(
+            (1..=MAX_PLAYERS).contains(&cpus.len()),
+            "The adapter takes 1 to {} players",
+            MAX_PLAYERS
+        );
+        let ports = Rc::new(RefCell::new(Ports::default()));
+        for (player, cpu) in cpus.iter_mut().enumerate() {
+            cpu.connect_serial(Box::new(AdapterPort {
+                ports: ports.clone(),
+                player,
+            }));
+        }
+        let dmg07 = Dmg07::new();
+        Self {
+            cpus,
+            ports,
+            next_byte: dmg07.byte_period(),
+            dmg07,
+        }
+    }
+
+    pub fn step(&mut self) {
+        // Stopped instances don't advance, so only the others run.
+        let running = self.cpus.iter_mut().filter(|cpu| !cpu.is_stopped());
+        let Some(cpu) = running.min_by_key(|cpu| cpu.cycles()) else {
+            return;
+        };
+        cpu.step();
+
+        let now = self
+            .cpus
+            .iter()
+            .filter(|cpu| !cpu.is_stopped())
+            .map(|cpu| cpu.cycles())
+            .min()
+            .unwrap_or(0);
+        while now >= self.next_byte {
+            self.clock();
+            self.next_byte += self.dmg07.byte_period();
+        }
+    }
+
+    fn clock(&mut self) {
+        let mut ports = self.ports.borrow_mut();
+        let sent = std::array::from_fn(|player| ports.waiting[player].take());
+        let replies = self.dmg07.clock(sent);
+        // A Game Boy without a transfer armed misses the byte.
+        for player in 0..self.cpus.len() {
+            if sent[player].is_some() {
+                ports.inbox[player] = Some(replies[player]);
+            }
+        }
+    }
+
+    pub fn players(&self) -> usize {
+        self.cpus.len()
+    }
+
+    pub fn cpu(&self, player: usize) -> &Cpu {
+        &self.cpus[player]
+    }
+
+    pub fn cpu_mut(&mut self, player: usize) -> &mut Cpu {
+        &mut self.cpus[player]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::test_rom;
+
+    fn ping_packet(dmg07: &mut Dmg07, sent: [[Option<u8>; MAX_PLAYERS]; 4]) -> Vec<[u8; 4]> {
+        sent.iter().map(|&bytes| dmg07.clock(bytes)).collect()
+    }
+
+    #[test]
+    fn test_ping_assigns_players() {
+        let mut dmg07 = Dmg07::new();
+        let ack = [Some(PING_ACK), Some(PING_ACK), None, None];
+        let rate = [Some(0x13), Some(0x10), None, None];
+        let size = [Some(0x02), Some(0x02), None, None];
+        ping_packet(&mut dmg07, [ack, ack, rate, size]);
+
+        let replies = ping_packet(&mut dmg07, [ack, ack, rate, size]);
+        assert_eq!(replies[0], [PING_ID; 4]);
+        assert_eq!(replies[1], [0x31, 0x32, 0x33, 0x34]);
+        assert_eq!(dmg07.rate, 0x13);
+        assert_eq!(dmg07.size, 2);
+        assert_eq!(
+            dmg07.byte_period(),
+            Dmg07::new().byte_period() + 3 * GAP_CYCLES_PER_RATE
+        );
+    }
+
+    #[test]
+    fn test_transmission_buffers_packets() {
+        let mut dmg07 = Dmg07::new();
+        let ack = [Some(PING_ACK), Some(PING_ACK), Some(PING_ACK), None];
+        let size = [Some(0x01); MAX_PLAYERS];
+        ping_packet(&mut dmg07, [ack, ack, size, size]);
+        let end = [Some(PING_END); MAX_PLAYERS];
+        ping_packet(&mut dmg07, [end; 4]);
+        for _ in 0..PING_PACKET_LEN {
+            assert_eq!(dmg07.clock([None; MAX_PLAYERS]), [TRANSMISSION_START; 4]);
+        }
+        assert_eq!(dmg07.phase, Phase::Transmission);
+
+        let round = [
+            [Some(0x11), Some(0x22), Some(0x33), Some(0x44)],
+            [None; 4],
+            [None; 4],
+            [None; 4],
+        ];
+        for bytes in round {
+            assert_eq!(dmg07.clock(bytes), [0x00; 4]);
+        }
+        let replies: Vec<u8> = round.iter().map(|&bytes| dmg07.clock(bytes)[0]).collect();
+        assert_eq!(replies, [0x11, 0x22, 0x33, 0xff]);
+
+        // Player 1 sending 0xFF goes back to pinging.
+        for _ in 0..MAX_PLAYERS {
+            dmg07.clock([Some(TRANSMISSION_RESET); MAX_PLAYERS]);
+        }
+        assert_eq!(dmg07.phase, Phase::Ping);
+    }
+
+    #[test]
+    fn test_adapter_clocks_external_transfers() {
+        let cpus = (0..3)
+            .map(|player| {
+                let mut cpu = Cpu::new();
+                cpu.load_rom(test_rom(&[0x76])).unwrap();
+                cpu.write_u8(0xff01, player);
+                cpu.write_u8(0xff02, 0x80);
+                cpu
+            })
+            .collect();
+        let mut adapter = FourPlayerAdapter::new(cpus);
+        while adapter.cpu(2).cycles() < Dmg07::new().byte_period() + 8 {
+            adapter.step();
+        }
+        for player in 0..adapter.players() {
+            assert_eq!(adapter.cpu_mut(player).read_u8(0xff01), PING_ID);
+            assert_eq!(adapter.cpu_mut(player).read_u8(0xff02) & 0x80, 0);
+        }
+    }
+}
@@ -0,0 +1,334 @@
+use std::io;
+use std::path::Path;
+
+use crate::cpu::{Cpu, Model};
+use crate::joypad::Buttons;
+use crate::ppu::Renderer;
+use crate::screenshot;
+use crate::serial::SerialDevice;
+
+/// CPU cycles in one frame at normal speed.
+pub const CYCLES_PER_FRAME: u64 = 70224;
+
+/// How a bounded run ended.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RunStatus {
+    /// Ran as long as asked.
+    Finished,
+    /// Gave up early because the CPU executed STOP and only a button press
+    /// can wake it.
+    Stopped,
+    /// The CPU executed LD B,B with software breakpoints on.
+    Breakpoint,
+}
+
+/// A whole Game Boy: the entry point for using the emulator as a library.
+///
+/// ```no_run
+/// use rust_gb::{Buttons, GameBoy, Model};
+///
+/// let mut gb = GameBoy::builder().model(Model::Cgb).build();
+/// gb.open_cartridge("game.gbc").unwrap();
+/// gb.set_buttons(Buttons { start: true, ..Buttons::default() });
+/// gb.run_frame();
+/// let pixels = gb.framebuffer();
+/// ```
+pub struct GameBoy {
+    cpu: Cpu,
+    options: GameBoyBuilder,
+}
+
+impl GameBoy {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> GameBoyBuilder {
+        GameBoyBuilder::new()
+    }
+
+    /// Powers on afresh with `rom` inserted. A dump that can't be run
+    /// leaves the Game Boy as it was.
+    pub fn load_cartridge(&mut self, rom: Vec<u8>) -> io::Result<()> {
+        let mut cpu = self.options.new_cpu();
+        cpu.load_rom(rom)?;
+        if let Some(model) = self.options.model {
+            cpu.set_model(model);
+        }
+        self.cpu = cpu;
+        Ok(())
+    }
+
+    pub fn open_cartridge<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.load_cartridge(std::fs::read(path)?)
+    }
+
+    pub fn model(&self) -> Model {
+        self.cpu.model()
+    }
+
+    /// Executes one instruction and returns the CPU cycles it took.
+    pub fn step(&mut self) -> u64 {
+        let start = self.cpu.cycles();
+        self.cpu.step();
+        self.cpu.cycles() - start
+    }
+
+    /// Runs until the next frame has been drawn. With the LCD off it
+    /// returns after a frame's worth of time instead.
+    pub fn run_frame(&mut self) -> RunStatus {
+        let frame = self.cpu.frames();
+        let speed = if self.cpu.double_speed() { 2 } else { 1 };
+        let end = self.cpu.cycles() + CYCLES_PER_FRAME * speed;
+        self.run_until(|gb| gb.frames() != frame || gb.cycles() >= end)
+    }
+
+    pub fn run_frames(&mut self, frames: u64) -> RunStatus {
+        for _ in 0..frames {
+            let status = self.run_frame();
+            if status != RunStatus::Finished {
+                return status;
+            }
+        }
+        RunStatus::Finished
+    }
+
+    /// Runs for at least `cycles` CPU cycles, finishing the last
+    /// instruction.
+    pub fn run_cycles(&mut self, cycles: u64) -> RunStatus {
+        let end = self.cpu.cycles() + cycles;
+        self.run_until(|gb| gb.cycles() >= end)
+    }
+
+    /// Runs until `predicate` holds, checking it before every instruction.
+    pub fn run_until<F: FnMut(&GameBoy) -> bool>(&mut self, mut predicate: F) -> RunStatus {
+        while !predicate(self) {
+            if self.cpu.is_stopped() {
+                return RunStatus::Stopped;
+            }
+            self.cpu.step();
+            if self.cpu.take_breakpoint() {
+                return RunStatus::Breakpoint;
+            }
+        }
+        RunStatus::Finished
+    }
+
+    /// RGB555 colours of the last drawn frame, row by row.
+    pub fn framebuffer(&self) -> &[u16] {
+        self.cpu.framebuffer()
+    }
+
+    /// Saves the last drawn frame as a PNG, with each pixel blown up to
+    /// `scale` by `scale`.
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P, scale: usize) -> io::Result<()> {
+        screenshot::save_png(path, self.cpu.framebuffer(), scale)
+    }
+
+    /// Frames drawn since the cartridge was loaded.
+    pub fn frames(&self) -> u64 {
+        self.cpu.frames()
+    }
+
+    /// CPU cycles executed since the cartridge was loaded.
+    pub fn cycles(&self) -> u64 {
+        self.cpu.cycles()
+    }
+
+    /// Number of audio samples waiting to be read.
+    pub fn samples_available(&self) -> usize {
+        self.cpu.samples_available()
+    }
+
+    /// Drains audio into `out` as interleaved left and right samples at
+    /// the builder's sample rate and returns how many were written.
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        self.cpu.read_samples(out)
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.cpu.set_buttons(buttons);
+    }
+
+    /// Battery-backed cartridge RAM, to be written to a save file.
+    pub fn save_ram(&self) -> &[u8] {
+        self.cpu.save_ram()
+    }
+
+    /// Restores a save file after `load_cartridge`.
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        self.cpu.load_save_ram(data);
+    }
+
+    /// Plugs `device` into the link port, replacing whatever was there.
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.cpu.connect_serial(device);
+    }
+
+    /// Every byte sent over the serial port so far.
+    pub fn serial_output(&self) -> &[u8] {
+        self.cpu.serial_output()
+    }
+
+    /// The underlying hardware, for what the facade doesn't cover such as
+    /// recording audio or poking at the APU.
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+}
+
+impl Default for GameBoy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Options for a `GameBoy`, kept so loading another cartridge powers on
+/// with the same ones.
+#[derive(Clone)]
+pub struct GameBoyBuilder {
+    model: Option<Model>,
+    renderer: Renderer,
+    sample_rate: Option<u32>,
+    serial_echo: bool,
+    software_breakpoints: bool,
+}
+
+impl GameBoyBuilder {
+    pub fn new() -> Self {
+        Self {
+            model: None,
+            renderer: Renderer::Scanline,
+            sample_rate: None,
+            serial_echo: false,
+            software_breakpoints: false,
+        }
+    }
+
+    /// Forces the model instead of picking it from the cartridge header.
+    pub fn model(mut self, model: Model) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    pub fn renderer(mut self, renderer: Renderer) -> Self {
+        self.renderer = renderer;
+        self
+    }
+
+    /// Sets the rate in Hz that audio is produced at.
+    pub fn sample_rate(mut self, rate: u32) -> Self {
+        self.sample_rate = Some(rate);
+        self
+    }
+
+    /// Prints bytes sent over the serial port to stdout as they go out.
+    pub fn serial_echo(mut self, echo: bool) -> Self {
+        self.serial_echo = echo;
+        self
+    }
+
+    /// Ends runs with `RunStatus::Breakpoint` on LD B,B, the way Mooneye
+    /// test ROMs signal they're done.
+    pub fn software_breakpoints(mut self, enabled: bool) -> Self {
+        self.software_breakpoints = enabled;
+        self
+    }
+
+    pub fn build(self) -> GameBoy {
+        let mut cpu = self.new_cpu();
+        if let Some(model) = self.model {
+            cpu.set_model(model);
+        }
+        GameBoy { cpu, options: self }
+    }
+
+    fn new_cpu(&self) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_renderer(self.renderer);
+        cpu.set_serial_echo(self.serial_echo);
+        cpu.set_software_breakpoints(self.software_breakpoints);
+        if let Some(rate) = self.sample_rate {
+            cpu.set_sample_rate(rate);
+        }
+        cpu
+    }
+}
+
+impl Default for GameBoyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::test_rom;
+
+    #[test]
+    fn test_builder_options_survive_reload() {
+        let mut gb = GameBoy::builder()
+            .model(Model::Cgb)
+            .sample_rate(48_000)
+            .build();
+        assert_eq!(gb.model(), Model::Cgb);
+
+        let mut rom = test_rom(&[]);
+        // ROM+RAM+BATTERY with 8 KiB of RAM.
+        rom[0x147] = 0x09;
+        rom[0x149] = 0x02;
+        gb.load_cartridge(rom).unwrap();
+        assert_eq!(gb.model(), Model::Cgb);
+        assert_eq!(gb.cpu().sample_rate(), 48_000);
+        assert_eq!(gb.save_ram().len(), 0x2000);
+
+        assert!(gb.load_cartridge(vec![0; 0x10000]).is_err());
+        assert_eq!(gb.save_ram().len(), 0x2000);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut gb = GameBoy::new();
+        // HALT with no interrupts enabled idles until the frame is drawn.
+        gb.load_cartridge(test_rom(&[0x76])).unwrap();
+        gb.run_frame();
+        assert_eq!(gb.frames(), 1);
+        gb.run_frame();
+        assert_eq!(gb.frames(), 2);
+        assert!(gb.cycles() <= 2 * CYCLES_PER_FRAME);
+
+        assert_eq!(gb.run_frames(3), RunStatus::Finished);
+        assert_eq!(gb.frames(), 5);
+        assert_eq!(gb.run_cycles(100), RunStatus::Finished);
+        assert_eq!(gb.run_until(|gb| gb.frames() == 6), RunStatus::Finished);
+    }
+
+    #[test]
+    fn test_run_gives_up_on_stop() {
+        let mut gb = GameBoy::new();
+        gb.load_cartridge(test_rom(&[0x10, 0x00])).unwrap();
+        assert_eq!(gb.run_until(|_| false), RunStatus::Stopped);
+        assert_eq!(gb.run_frames(10), RunStatus::Stopped);
+        assert_eq!(gb.frames(), 0);
+    }
+
+    #[test]
+    fn test_software_breakpoint() {
+        // LD B,B / HALT
+        let rom = test_rom(&[0x40, 0x76]);
+        let mut gb = GameBoy::new();
+        gb.load_cartridge(rom.clone()).unwrap();
+        assert_eq!(gb.run_frame(), RunStatus::Finished);
+
+        let mut gb = GameBoy::builder().software_breakpoints(true).build();
+        gb.load_cartridge(rom).unwrap();
+        assert_eq!(gb.run_frame(), RunStatus::Breakpoint);
+        assert_eq!(gb.cpu().registers().pc, 0x0101);
+        assert_eq!(gb.run_frame(), RunStatus::Finished);
+    }
+}
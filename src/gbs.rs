@@ -0,0 +1,283 @@
+// https://ocremix.org/info/GBS_Format_Specification
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+use crate::cpu::{Cpu, Model, KEY1_SWITCH_ARMED, MAX_ROM_IMAGE_SIZE};
+use crate::wav::AudioRecorder;
+
+const HEADER_SIZE: usize = 0x70;
+const MAGIC: &[u8; 3] = b"GBS";
+
+const TAC_TIMER_ENABLE: u8 = 0b0000_0100;
+/// Not part of the real TAC: GBS files set it for tunes that ran in CGB
+/// double speed mode.
+const TAC_DOUBLE_SPEED: u8 = 0b1000_0000;
+
+/// Where the routines return to: a HALT for the CPU to idle on until the
+/// next call to play. GBS code is always loaded at 0x400 or above.
+const IDLE_ADDR: u16 = 0x0100;
+const HALT: u8 = 0x76;
+
+/// CPU cycles between two VBlanks, the play rate unless the timer is used.
+const VBLANK_PERIOD: u64 = 70224;
+/// How long init and play may run before they're given up on.
+const ROUTINE_TIMEOUT: u64 = 4_194_304;
+
+/// A GBS file: the music code ripped from a game plus the addresses and
+/// timer settings needed to play it.
+pub struct Gbs {
+    pub songs: u8,
+    /// The song to play by default, starting from 1.
+    pub first_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub sp: u16,
+    pub tma: u8,
+    pub tac: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    code: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..3] != MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a GBS file"));
+        }
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let text = |offset: usize| {
+            let field = &bytes[offset..offset + 32];
+            let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+        let gbs = Self {
+            songs: bytes[0x04],
+            first_song: bytes[0x05],
+            load_addr: word(0x06),
+            init_addr: word(0x08),
+            play_addr: word(0x0a),
+            sp: word(0x0c),
+            tma: bytes[0x0e],
+            tac: bytes[0x0f],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+            code: bytes[HEADER_SIZE..].to_vec(),
+        };
+        if gbs.load_addr < 0x400 || gbs.load_addr >= 0x8000 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "bad GBS load address",
+            ));
+        }
+        if gbs.load_addr as usize + gbs.code.len() > MAX_ROM_IMAGE_SIZE {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "GBS file has more banks than can be switched in",
+            ));
+        }
+        Ok(gbs)
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+
+    /// Whether the tune runs with the CPU in double speed mode.
+    pub fn double_speed(&self) -> bool {
+        self.tac & TAC_DOUBLE_SPEED != 0
+    }
+
+    /// CPU cycles between calls to play: either the rate of the timer set
+    /// up by TMA and TAC, or once per VBlank. In double speed the timer
+    /// keeps pace with the CPU but a VBlank takes twice the cycles.
+    pub fn play_period(&self) -> u64 {
+        if self.tac & TAC_TIMER_ENABLE == 0 {
+            return VBLANK_PERIOD * self.speed();
+        }
+        let cycles_per_tick = match self.tac & 0x03 {
+            0b00 => 1024,
+            0b01 => 16,
+            0b10 => 64,
+            _ => 256,
+        };
+        (256 - self.tma as u64) * cycles_per_tick
+    }
+
+    fn speed(&self) -> u64 {
+        if self.double_speed() {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+/// Plays a GBS file on a `Cpu`, calling play at the rate the file asks for.
+///
+/// The file is mapped from its load address with MBC1 bank switching, so
+/// writes to 0x2000-0x3FFF page in the banks past 0x7FFF.
+pub struct GbsPlayer {
+    gbs: Gbs,
+    cpu: Cpu,
+    next_play: u64,
+}
+
+impl GbsPlayer {
+    pub fn new(gbs: Gbs) -> Self {
+        Self {
+            gbs,
+            cpu: Cpu::new(),
+            next_play: 0,
+        }
+    }
+
+    pub fn gbs(&self) -> &Gbs {
+        &self.gbs
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// Resets the Game Boy and runs init for `song`, counting from 1.
+    pub fn start_song(&mut self, song: u8) {
+        let mut cpu = Cpu::new();
+        cpu.set_sample_rate(self.cpu.sample_rate());
+        let mut image = vec![0; self.gbs.load_addr as usize];
+        image.extend_from_slice(&self.gbs.code);
+        image[IDLE_ADDR as usize] = HALT;
+        cpu.load_rom_image(image);
+        if self.gbs.double_speed() {
+            // Switched the way a game would, so the timer and APU follow.
+            cpu.set_model(Model::Cgb);
+            cpu.write_u8(0xff4d, KEY1_SWITCH_ARMED);
+            cpu.stop();
+        }
+        cpu.write_u8(0xff06, self.gbs.tma);
+        cpu.write_u8(0xff07, self.gbs.tac & !TAC_DOUBLE_SPEED);
+        cpu.set_sp(self.gbs.sp);
+        self.cpu = cpu;
+
+        self.call(self.gbs.init_addr, song.saturating_sub(1));
+        self.next_play = self.cpu.cycles();
+    }
+
+    /// Runs for `cycles` CPU cycles, calling play whenever it's due.
+    pub fn run_cycles(&mut self, cycles: u64) {
+        let end = self.cpu.cycles() + cycles;
+        while self.cpu.cycles() < end {
+            if self.cpu.cycles() >= self.next_play {
+                self.next_play += self.gbs.play_period();
+                self.call(self.gbs.play_addr, 0);
+            }
+            self.cpu.step();
+        }
+    }
+
+    /// Calls a routine and runs it until it returns to the idle HALT.
+    fn call(&mut self, addr: u16, a: u8) {
+        self.cpu.call(addr, IDLE_ADDR, a);
+        let timeout = self.cpu.cycles() + ROUTINE_TIMEOUT;
+        while self.cpu.pc() != IDLE_ADDR && self.cpu.cycles() < timeout {
+            self.cpu.step();
+        }
+    }
+}
+
+/// Plays `song` for `seconds` and writes what it sounds like to `path`.
+pub fn render_track<P: AsRef<Path>>(
+    player: &mut GbsPlayer,
+    song: u8,
+    seconds: u32,
+    path: P,
+) -> io::Result<()> {
+    player.start_song(song);
+    let mut recorder = AudioRecorder::create(player.cpu_mut(), path, false)?;
+    // A frame's worth at a time keeps the sample buffer from overflowing.
+    let speed = player.gbs().speed();
+    for _ in 0..seconds as u64 * crate::apu::CLOCK_RATE as u64 / VBLANK_PERIOD {
+        player.run_cycles(VBLANK_PERIOD * speed);
+        recorder.record(player.cpu_mut())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gbs_file(code: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[0..4].copy_from_slice(b"GBS\x01");
+        bytes[0x04] = 3;
+        bytes[0x05] = 1;
+        bytes[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        bytes[0x08..0x0a].copy_from_slice(&0x0400u16.to_le_bytes());
+        bytes[0x0a..0x0c].copy_from_slice(&0x0401u16.to_le_bytes());
+        bytes[0x0c..0x0e].copy_from_slice(&0xdffeu16.to_le_bytes());
+        bytes[0x10..0x15].copy_from_slice(b"Title");
+        bytes.extend_from_slice(code);
+        bytes
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let gbs = Gbs::from_bytes(&gbs_file(&[0xc9])).unwrap();
+        assert_eq!(gbs.songs, 3);
+        assert_eq!(gbs.play_addr, 0x0401);
+        assert_eq!(gbs.sp, 0xdffe);
+        assert_eq!(gbs.title, "Title");
+        assert_eq!(gbs.author, "");
+        assert_eq!(gbs.play_period(), VBLANK_PERIOD);
+
+        assert!(Gbs::from_bytes(b"GBX").is_err());
+    }
+
+    #[test]
+    fn test_timer_play_period() {
+        let mut bytes = gbs_file(&[0xc9]);
+        bytes[0x0e] = 0xc0;
+        bytes[0x0f] = TAC_TIMER_ENABLE | 0b10;
+        let gbs = Gbs::from_bytes(&bytes).unwrap();
+        assert_eq!(gbs.play_period(), 64 * 64);
+
+        bytes[0x0f] |= TAC_DOUBLE_SPEED;
+        let gbs = Gbs::from_bytes(&bytes).unwrap();
+        assert_eq!(gbs.play_period(), 64 * 64);
+        let mut player = GbsPlayer::new(gbs);
+        player.start_song(1);
+        assert!(player.cpu.double_speed());
+        assert_eq!(player.cpu.read_u8(0xff07), 0xf8 | TAC_TIMER_ENABLE | 0b10);
+    }
+
+    #[test]
+    fn test_banked_code() {
+        // Loaded at 0x400, bank 2 starts 0x7C00 bytes into the code.
+        let mut code = vec![0xc9; 0x7c00 + 1];
+        code[0x7c00] = 0x42;
+        let gbs = Gbs::from_bytes(&gbs_file(&code)).unwrap();
+        let mut player = GbsPlayer::new(gbs);
+        player.start_song(1);
+        assert_eq!(player.cpu.read_u8(0x4000), 0xc9);
+        player.cpu.write_u8(0x2000, 2);
+        assert_eq!(player.cpu.read_u8(0x4000), 0x42);
+
+        assert!(Gbs::from_bytes(&gbs_file(&vec![0; MAX_ROM_IMAGE_SIZE])).is_err());
+    }
+
+    #[test]
+    fn test_routines_return_to_idle() {
+        // init: RET, play: LDH A,(0x26) / RET
+        let gbs = Gbs::from_bytes(&gbs_file(&[0xc9, 0xf0, 0x26, 0xc9])).unwrap();
+        let mut player = GbsPlayer::new(gbs);
+        player.start_song(1);
+        assert_eq!(player.cpu.pc(), IDLE_ADDR);
+
+        player.run_cycles(VBLANK_PERIOD * 3);
+        assert_eq!(player.cpu.pc(), IDLE_ADDR + 1);
+        assert!(player.next_play > VBLANK_PERIOD * 2);
+    }
+}
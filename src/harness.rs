@@ -0,0 +1,427 @@
+// https://github.com/retrio/gb-test-roms
+// https://github.com/Gekkio/mooneye-test-suite#passfail-reporting
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use crate::cpu::Registers;
+use crate::gameboy::{GameBoy, RunStatus};
+
+/// How long a ROM gets to report a result: two minutes of emulated time.
+pub const DEFAULT_FRAME_LIMIT: u64 = 60 * 120;
+/// Frames run before a framebuffer-checked ROM's screen is hashed.
+pub const FRAMEBUFFER_FRAMES: u64 = 300;
+
+/// What Mooneye tests leave in B, C, D, E, H and L when they reach their
+/// LD B,B breakpoint after passing, and what every one of them holds when
+/// they fail.
+const FIBONACCI: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const FAILURE: [u8; 6] = [0x42; 6];
+
+/// How a ROM's result was read.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Check {
+    /// Blargg's ROMs print "Passed" or "Failed" over the serial port.
+    Serial,
+    /// Mooneye's ROMs load Fibonacci numbers into the registers and hit an
+    /// LD B,B breakpoint.
+    Registers,
+    /// acid2 and the like are compared with a golden framebuffer hash.
+    Framebuffer,
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Check::Serial => "serial",
+            Check::Registers => "registers",
+            Check::Framebuffer => "framebuffer",
+        };
+        f.pad(name)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// Nothing was reported within the frame limit.
+    Timeout,
+    /// A new golden framebuffer hash was written instead of checked.
+    Recorded,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        *self == Outcome::Pass
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail(reason) => write!(f, "FAIL: {}", reason),
+            Outcome::Timeout => write!(f, "TIMEOUT"),
+            Outcome::Recorded => write!(f, "recorded"),
+        }
+    }
+}
+
+pub struct RomResult {
+    pub path: PathBuf,
+    /// `None` if the ROM never reported anything.
+    pub check: Option<Check>,
+    pub outcome: Outcome,
+    pub frames: u64,
+}
+
+/// Runs test ROMs headlessly and decides whether they passed.
+///
+/// A ROM with a `<name>.hash` file next to it is checked against that
+/// framebuffer hash, and fails while the file is empty. `update_golden`
+/// records the current hash instead. Any other ROM runs until it reports
+/// through the serial port or its registers.
+pub struct Harness {
+    frame_limit: u64,
+    framebuffer_frames: u64,
+    update_golden: bool,
+}
+
+impl Harness {
+    pub fn new() -> Self {
+        Self {
+            frame_limit: DEFAULT_FRAME_LIMIT,
+            framebuffer_frames: FRAMEBUFFER_FRAMES,
+            update_golden: false,
+        }
+    }
+
+    pub fn set_frame_limit(&mut self, frames: u64) {
+        self.frame_limit = frames;
+    }
+
+    /// Sets how long framebuffer-checked ROMs run before the hash is taken.
+    pub fn set_framebuffer_frames(&mut self, frames: u64) {
+        self.framebuffer_frames = frames;
+    }
+
+    pub fn set_update_golden(&mut self, update: bool) {
+        self.update_golden = update;
+    }
+
+    /// Runs every `.gb` and `.gbc` file under `dir`, in path order.
+    pub fn run_dir<P: AsRef<Path>>(&self, dir: P) -> io::Result<Vec<RomResult>> {
+        let mut roms = Vec::new();
+        find_roms(dir.as_ref(), &mut roms)?;
+        roms.sort();
+        roms.iter().map(|rom| self.run_rom(rom)).collect()
+    }
+
+    pub fn run_rom<P: AsRef<Path>>(&self, path: P) -> io::Result<RomResult> {
+        let path = path.as_ref();
+        let mut gb = GameBoy::builder().software_breakpoints(true).build();
+        if let Err(err) = gb.open_cartridge(path) {
+            return Ok(RomResult {
+                path: path.to_path_buf(),
+                check: None,
+                outcome: Outcome::Fail(format!("can't load: {}", err)),
+                frames: 0,
+            });
+        }
+
+        let golden = golden_path(path);
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            if golden.exists() {
+                self.check_framebuffer(&mut gb, &golden)
+            } else {
+                Ok(self.wait_for_report(&mut gb))
+            }
+        }));
+        let (check, outcome) = match run {
+            Ok(result) => result?,
+            Err(panic) => (None, Outcome::Fail(panic_message(&panic))),
+        };
+        Ok(RomResult {
+            path: path.to_path_buf(),
+            check,
+            outcome,
+            frames: gb.frames(),
+        })
+    }
+
+    fn wait_for_report(&self, gb: &mut GameBoy) -> (Option<Check>, Outcome) {
+        for _ in 0..self.frame_limit {
+            let status = gb.run_frame();
+            if let Some(outcome) = serial_report(gb.serial_output()) {
+                return (Some(Check::Serial), outcome);
+            }
+            match status {
+                RunStatus::Breakpoint => {
+                    let outcome = mooneye_outcome(gb.cpu().registers());
+                    return (Some(Check::Registers), outcome);
+                }
+                RunStatus::Stopped => {
+                    return (None, Outcome::Fail("stopped with STOP".to_string()));
+                }
+                RunStatus::Finished => {}
+            }
+        }
+        (None, Outcome::Timeout)
+    }
+
+    fn check_framebuffer(
+        &self,
+        gb: &mut GameBoy,
+        golden: &Path,
+    ) -> io::Result<(Option<Check>, Outcome)> {
+        let reason = match gb.run_frames(self.framebuffer_frames) {
+            RunStatus::Finished => None,
+            RunStatus::Stopped => Some("stopped with STOP"),
+            RunStatus::Breakpoint => Some("hit a breakpoint"),
+        };
+        if let Some(reason) = reason {
+            let reason = format!("{} before the screen was hashed", reason);
+            return Ok((Some(Check::Framebuffer), Outcome::Fail(reason)));
+        }
+
+        let hash = format!("{:016x}", framebuffer_hash(gb.framebuffer()));
+        let expected = std::fs::read_to_string(golden)?;
+        let expected = expected.trim();
+
+        let outcome = if self.update_golden {
+            std::fs::write(golden, format!("{}\n", hash))?;
+            Outcome::Recorded
+        } else if expected.is_empty() {
+            Outcome::Fail(format!("no golden hash, {} would be recorded", hash))
+        } else if expected == hash {
+            Outcome::Pass
+        } else {
+            // Keep what it looked like for comparing by eye.
+            gb.save_screenshot(golden.with_extension("actual.png"), 1)?;
+            Outcome::Fail(format!("hash {} != golden {}", hash, expected))
+        };
+        Ok((Some(Check::Framebuffer), outcome))
+    }
+}
+
+impl Default for Harness {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// FNV-1a over the framebuffer, stable across platforms and Rust versions
+/// unlike `DefaultHasher`.
+pub fn framebuffer_hash(framebuffer: &[u16]) -> u64 {
+    framebuffer
+        .iter()
+        .flat_map(|color| color.to_le_bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+/// Lines up results as ROM, check, frames and outcome, with a count of
+/// passes at the bottom.
+pub fn summary_table(results: &[RomResult]) -> String {
+    let names: Vec<String> = results
+        .iter()
+        .map(|result| result.path.display().to_string())
+        .collect();
+    let width = names.iter().map(String::len).max().unwrap_or(0).max(3);
+
+    let mut table = format!(
+        "{:width$}  {:11}  {:>6}  Result\n",
+        "ROM",
+        "Check",
+        "Frames",
+        width = width
+    );
+    for (name, result) in names.iter().zip(results) {
+        let check = result
+            .check
+            .map_or("-".to_string(), |check| check.to_string());
+        table += &format!(
+            "{:width$}  {:11}  {:>6}  {}\n",
+            name,
+            check,
+            result.frames,
+            result.outcome,
+            width = width
+        );
+    }
+    let passed = results
+        .iter()
+        .filter(|result| result.outcome.passed())
+        .count();
+    table += &format!("{}/{} passed\n", passed, results.len());
+    table
+}
+
+fn serial_report(output: &[u8]) -> Option<Outcome> {
+    let text = String::from_utf8_lossy(output);
+    if text.contains("Passed") {
+        Some(Outcome::Pass)
+    } else if text.contains("Failed") {
+        let reason = text.lines().rfind(|line| !line.trim().is_empty());
+        Some(Outcome::Fail(reason.unwrap_or("Failed").trim().to_string()))
+    } else {
+        None
+    }
+}
+
+/// Reads a Mooneye result from the registers at its LD B,B breakpoint.
+pub fn mooneye_outcome(reg: &Registers) -> Outcome {
+    let values = [reg.b, reg.c, reg.d, reg.e, reg.h, reg.l];
+    if values == FIBONACCI {
+        Outcome::Pass
+    } else if values == FAILURE {
+        Outcome::Fail("registers hold 0x42".to_string())
+    } else {
+        Outcome::Fail(format!(
+            "B={:02X} C={:02X} D={:02X} E={:02X} H={:02X} L={:02X}",
+            reg.b, reg.c, reg.d, reg.e, reg.h, reg.l
+        ))
+    }
+}
+
+fn golden_path(rom: &Path) -> PathBuf {
+    let name = rom.file_name().unwrap_or_default().to_string_lossy();
+    rom.with_file_name(format!("{}.hash", name))
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_roms(&path, roms)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext == "gb" || ext == "gbc")
+        {
+            roms.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Describes a panic caught with `catch_unwind`.
+pub(crate) fn panic_message(panic: &Box<dyn std::any::Any + Send>) -> String {
+    let message = panic
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| panic.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+    format!("panicked: {}", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::test_rom;
+
+    #[test]
+    fn test_serial_report() {
+        assert_eq!(serial_report(b"01-special\n\n"), None);
+        assert_eq!(
+            serial_report(b"01-special\n\nPassed\n"),
+            Some(Outcome::Pass)
+        );
+        assert_eq!(
+            serial_report(b"01-special\n\nFailed #3\n"),
+            Some(Outcome::Fail("Failed #3".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_mooneye_breakpoint() {
+        let dir = std::env::temp_dir().join(format!("rust-gb-mooneye-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // LD B,H with H = 0x01 from the boot ROM, then LD B,B.
+        let rom = dir.join("fail.gb");
+        std::fs::write(&rom, test_rom(&[0x44, 0x40, 0x76])).unwrap();
+        let result = Harness::new().run_rom(&rom).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(result.check, Some(Check::Registers));
+        assert_eq!(
+            result.outcome,
+            Outcome::Fail("B=01 C=13 D=00 E=D8 H=01 L=4D".to_string())
+        );
+
+        let mut reg = Registers::new();
+        (reg.b, reg.c, reg.d, reg.e, reg.h, reg.l) = (3, 5, 8, 13, 21, 34);
+        assert_eq!(mooneye_outcome(&reg), Outcome::Pass);
+    }
+
+    #[test]
+    fn test_summary_table() {
+        let results = vec![
+            RomResult {
+                path: PathBuf::from("cpu_instrs.gb"),
+                check: Some(Check::Serial),
+                outcome: Outcome::Pass,
+                frames: 1234,
+            },
+            RomResult {
+                path: PathBuf::from("halt.gb"),
+                check: None,
+                outcome: Outcome::Timeout,
+                frames: 7200,
+            },
+        ];
+        let table = summary_table(&results);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "ROM            Check        Frames  Result");
+        assert_eq!(lines[1], "cpu_instrs.gb  serial         1234  pass");
+        assert_eq!(lines[2], "halt.gb        -              7200  TIMEOUT");
+        assert_eq!(lines[3], "1/2 passed");
+    }
+
+    #[test]
+    fn test_framebuffer_golden() {
+        let dir = std::env::temp_dir().join(format!("rust-gb-harness-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("halt.gb");
+        std::fs::write(&rom, test_rom(&[0x76])).unwrap();
+        std::fs::write(golden_path(&rom), "").unwrap();
+
+        let mut harness = Harness::new();
+        harness.set_framebuffer_frames(3);
+        let empty = harness.run_rom(&rom).unwrap();
+        assert_eq!(empty.check, Some(Check::Framebuffer));
+        assert!(!empty.outcome.passed());
+        assert!(std::fs::read_to_string(golden_path(&rom))
+            .unwrap()
+            .is_empty());
+
+        harness.set_update_golden(true);
+        assert_eq!(harness.run_rom(&rom).unwrap().outcome, Outcome::Recorded);
+        harness.set_update_golden(false);
+        assert_eq!(harness.run_dir(&dir).unwrap()[0].outcome, Outcome::Pass);
+
+        std::fs::write(golden_path(&rom), "0123456789abcdef").unwrap();
+        let mismatch = harness.run_rom(&rom).unwrap();
+        assert!(!mismatch.outcome.passed());
+
+        let stop = dir.join("stop.gb");
+        std::fs::write(&stop, test_rom(&[0x10, 0x00])).unwrap();
+        std::fs::write(golden_path(&stop), "0123456789abcdef").unwrap();
+        assert_eq!(
+            harness.run_rom(&stop).unwrap().outcome,
+            Outcome::Fail("stopped with STOP before the screen was hashed".to_string())
+        );
+        std::fs::remove_file(&stop).unwrap();
+
+        // A bad dump is a failed row, not the end of the run.
+        std::fs::write(dir.join("bad.gb"), [0x76]).unwrap();
+        let results = harness.run_dir(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(results.len(), 2);
+        assert!(
+            matches!(&results[0].outcome, Outcome::Fail(reason) if reason.starts_with("can't load"))
+        );
+    }
+}
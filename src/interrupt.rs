@@ -0,0 +1,9 @@
+// https://gbdev.io/pandocs/Interrupt_Sources.html
+pub const VBLANK: u8 = 0b0000_0001;
+pub const LCD_STAT: u8 = 0b0000_0010;
+pub const TIMER: u8 = 0b0000_0100;
+pub const SERIAL: u8 = 0b0000_1000;
+pub const JOYPAD: u8 = 0b0001_0000;
+
+pub const IF_ADDR: u16 = 0xff0f;
+pub const IE_ADDR: u16 = 0xffff;
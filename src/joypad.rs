@@ -0,0 +1,137 @@
+// https://gbdev.io/pandocs/Joypad_Input.html
+use crate::interrupt;
+
+const SELECT_BUTTONS: u8 = 0b0010_0000;
+const SELECT_DPAD: u8 = 0b0001_0000;
+
+/// The state of all eight buttons, `true` meaning pressed.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Buttons {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+impl Buttons {
+    /// Active-high bits in P1 order: A/Right in bit 0 up to Start/Down in bit 3.
+    fn dpad_bits(&self) -> u8 {
+        self.right as u8 | (self.left as u8) << 1 | (self.up as u8) << 2 | (self.down as u8) << 3
+    }
+
+    fn button_bits(&self) -> u8 {
+        self.a as u8 | (self.b as u8) << 1 | (self.select as u8) << 2 | (self.start as u8) << 3
+    }
+}
+
+pub struct Joypad {
+    select: u8,
+    buttons: Buttons,
+    interrupts: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: SELECT_BUTTONS | SELECT_DPAD,
+            buttons: Buttons::default(),
+            interrupts: 0,
+        }
+    }
+
+    /// Returns and clears the interrupt flags requested since the last call.
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        let old = self.lines();
+        self.buttons = buttons;
+        self.check_interrupt(old);
+    }
+
+    /// Whether a button in a selected group is held, which is what takes the
+    /// CPU out of STOP.
+    pub fn any_selected_pressed(&self) -> bool {
+        self.lines() != 0x0f
+    }
+
+    pub fn read(&self) -> u8 {
+        0xc0 | self.select | self.lines()
+    }
+
+    pub fn write(&mut self, byte: u8) {
+        let old = self.lines();
+        self.select = byte & (SELECT_BUTTONS | SELECT_DPAD);
+        self.check_interrupt(old);
+    }
+
+    /// The four input lines, low when a button of a selected group is held.
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & SELECT_DPAD == 0 {
+            pressed |= self.buttons.dpad_bits();
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            pressed |= self.buttons.button_bits();
+        }
+        !pressed & 0x0f
+    }
+
+    /// The interrupt fires when any line goes from high to low.
+    fn check_interrupt(&mut self, old: u8) {
+        if old & !self.lines() != 0 {
+            self.interrupts |= interrupt::JOYPAD;
+        }
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix_selection() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(Buttons {
+            a: true,
+            down: true,
+            ..Buttons::default()
+        });
+        assert_eq!(joypad.read(), 0xff);
+
+        joypad.write(SELECT_BUTTONS);
+        assert_eq!(joypad.read(), 0xc0 | SELECT_BUTTONS | 0b0111);
+        joypad.write(SELECT_DPAD);
+        assert_eq!(joypad.read(), 0xc0 | SELECT_DPAD | 0b1110);
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xc0 | 0b0110);
+    }
+
+    #[test]
+    fn test_interrupt_on_press() {
+        let mut joypad = Joypad::new();
+        joypad.write(SELECT_BUTTONS);
+        joypad.set_buttons(Buttons {
+            start: true,
+            ..Buttons::default()
+        });
+        assert_eq!(joypad.take_interrupts(), 0);
+
+        joypad.write(SELECT_DPAD);
+        assert_eq!(joypad.take_interrupts(), interrupt::JOYPAD);
+
+        joypad.set_buttons(Buttons::default());
+        assert_eq!(joypad.take_interrupts(), 0);
+    }
+}
@@ -0,0 +1,22 @@
+pub mod apu;
+pub mod cpu;
+pub mod four_player;
+pub mod gameboy;
+pub mod gbs;
+pub mod harness;
+pub mod interrupt;
+pub mod joypad;
+pub mod link;
+pub mod ppu;
+pub mod printer;
+pub mod screenshot;
+pub mod serial;
+pub mod timer;
+pub mod trace_diff;
+pub mod vgm;
+pub mod wav;
+
+pub use cpu::{Cpu, Model};
+pub use gameboy::{GameBoy, GameBoyBuilder, RunStatus};
+pub use joypad::Buttons;
+pub use ppu::{Renderer, SCREEN_HEIGHT, SCREEN_WIDTH};
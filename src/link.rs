@@ -0,0 +1,253 @@
+// https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+use std::cell::RefCell;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+
+use crate::cpu::Cpu;
+use crate::serial::SerialDevice;
+
+/// Both ends of an in-process cable. `waiting` is the SB of a side that has a
+/// transfer armed on the external clock, `inbox` the byte the other side
+/// clocked into it.
+#[derive(Default)]
+struct Wire {
+    waiting: [Option<u8>; 2],
+    inbox: [Option<u8>; 2],
+}
+
+/// One plug of an in-process link cable.
+pub struct LinkPort {
+    wire: Rc<RefCell<Wire>>,
+    side: usize,
+}
+
+impl LinkPort {
+    /// Creates the two plugs of a new cable.
+    pub fn pair() -> (LinkPort, LinkPort) {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        (
+            LinkPort {
+                wire: wire.clone(),
+                side: 0,
+            },
+            LinkPort { wire, side: 1 },
+        )
+    }
+}
+
+impl SerialDevice for LinkPort {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut wire = self.wire.borrow_mut();
+        let other = 1 - self.side;
+        match wire.waiting[other].take() {
+            Some(received) => {
+                wire.inbox[other] = Some(byte);
+                received
+            }
+            // The other side isn't listening, so its line stays high.
+            None => 0xff,
+        }
+    }
+
+    fn poll(&mut self, waiting: Option<u8>) -> Option<u8> {
+        let mut wire = self.wire.borrow_mut();
+        if let Some(received) = wire.inbox[self.side].take() {
+            return Some(received);
+        }
+        wire.waiting[self.side] = waiting;
+        None
+    }
+}
+
+/// Two emulator instances in one process whose serial ports are connected.
+///
+/// `step` always advances whichever instance is behind, so the two never
+/// drift further apart than one instruction.
+pub struct LinkCable {
+    cpus: [Cpu; 2],
+}
+
+impl LinkCable {
+    pub fn new(mut first: Cpu, mut second: Cpu) -> Self {
+        let (a, b) = LinkPort::pair();
+        first.connect_serial(Box::new(a));
+        second.connect_serial(Box::new(b));
+        Self {
+            cpus: [first, second],
+        }
+    }
+
+    pub fn step(&mut self) {
+        let [first, second] = &mut self.cpus;
+        // A stopped instance doesn't advance, so only its partner runs.
+        if first.is_stopped() || (!second.is_stopped() && second.cycles() < first.cycles()) {
+            second.step();
+        } else {
+            first.step();
+        }
+    }
+
+    pub fn cpu(&self, index: usize) -> &Cpu {
+        &self.cpus[index]
+    }
+
+    pub fn cpu_mut(&mut self, index: usize) -> &mut Cpu {
+        &mut self.cpus[index]
+    }
+}
+
+const MSG_TRANSFER: u8 = 0x01;
+const MSG_REPLY: u8 = 0x02;
+
+/// Polls between looks at the socket while no transfer is armed. Transfers
+/// to this side still need a reply, but it can come a little late.
+const IDLE_POLL_INTERVAL: u32 = 64;
+
+/// A link cable to an emulator in another process over TCP.
+///
+/// Every byte clocked on the internal clock is sent as a transfer message
+/// and blocks until the other side replies with the byte it shifted out.
+pub struct TcpLink {
+    stream: TcpStream,
+    idle_polls: u32,
+}
+
+impl TcpLink {
+    /// Waits for the other emulator to connect.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let (stream, _) = TcpListener::bind(addr)?.accept()?;
+        Self::from_stream(stream)
+    }
+
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        Self::from_stream(TcpStream::connect(addr)?)
+    }
+
+    /// The socket stays non-blocking so `poll` can peek at it cheaply, and
+    /// only blocks for the length of a transfer.
+    fn from_stream(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            idle_polls: 0,
+        })
+    }
+
+    fn send(&mut self, kind: u8, byte: u8) -> io::Result<()> {
+        self.stream.write_all(&[kind, byte])
+    }
+
+    fn transfer(&mut self, byte: u8) -> io::Result<u8> {
+        self.stream.set_nonblocking(false)?;
+        let received = self.wait_for_reply(byte);
+        self.stream.set_nonblocking(true)?;
+        received
+    }
+
+    fn wait_for_reply(&mut self, byte: u8) -> io::Result<u8> {
+        self.send(MSG_TRANSFER, byte)?;
+        loop {
+            let mut message = [0; 2];
+            self.stream.read_exact(&mut message)?;
+            match message {
+                [MSG_REPLY, received] => return Ok(received),
+                // Both sides drove the clock at once; neither is listening.
+                [MSG_TRANSFER, _] => self.send(MSG_REPLY, 0xff)?,
+                _ => return Err(io::Error::new(ErrorKind::InvalidData, "bad link message")),
+            }
+        }
+    }
+
+    fn receive(&mut self, waiting: Option<u8>) -> io::Result<Option<u8>> {
+        if waiting.is_none() {
+            self.idle_polls += 1;
+            if self.idle_polls < IDLE_POLL_INTERVAL {
+                return Ok(None);
+            }
+        }
+        self.idle_polls = 0;
+        let mut message = [0; 2];
+        match self.stream.peek(&mut message) {
+            Ok(2) => {}
+            Ok(_) => return Ok(None),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(None),
+            Err(err) => return Err(err),
+        }
+        // Both bytes are already buffered, so this doesn't block.
+        self.stream.read_exact(&mut message)?;
+        match message {
+            [MSG_TRANSFER, received] => {
+                self.send(MSG_REPLY, waiting.unwrap_or(0xff))?;
+                Ok(waiting.map(|_| received))
+            }
+            _ => Err(io::Error::new(ErrorKind::InvalidData, "bad link message")),
+        }
+    }
+}
+
+impl SerialDevice for TcpLink {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        // A dropped connection looks like an unplugged cable.
+        self.transfer(byte).unwrap_or(0xff)
+    }
+
+    fn poll(&mut self, waiting: Option<u8>) -> Option<u8> {
+        self.receive(waiting).unwrap_or(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::test_rom;
+    use std::thread;
+
+    fn halted_cpu(sb: u8) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_rom(test_rom(&[0x76])).unwrap();
+        cpu.write_u8(0xff01, sb);
+        cpu
+    }
+
+    #[test]
+    fn test_link_cable_exchange() {
+        let mut cable = LinkCable::new(halted_cpu(0x12), halted_cpu(0x34));
+        cable.cpu_mut(1).write_u8(0xff02, 0x80);
+        cable.cpu_mut(0).write_u8(0xff02, 0x81);
+        while cable.cpu(0).cycles() < 8 * 512 + 8 {
+            cable.step();
+        }
+        assert_eq!(cable.cpu_mut(0).read_u8(0xff01), 0x34);
+        assert_eq!(cable.cpu_mut(1).read_u8(0xff01), 0x12);
+        assert_eq!(cable.cpu_mut(1).read_u8(0xff02) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_tcp_link_exchange() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (done, finished) = std::sync::mpsc::channel();
+        let slave = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut link = TcpLink::from_stream(stream).unwrap();
+            let received = loop {
+                if let Some(received) = link.poll(Some(0x34)) {
+                    break received;
+                }
+            };
+            // Nothing armed: transfers are still answered, just not taken.
+            while finished.try_recv().is_err() {
+                assert_eq!(link.poll(None), None);
+            }
+            received
+        });
+
+        let mut master = TcpLink::connect(addr).unwrap();
+        assert_eq!(master.exchange(0x12), 0x34);
+        assert_eq!(master.exchange(0x56), 0xff);
+        done.send(()).unwrap();
+        assert_eq!(slave.join().unwrap(), 0x12);
+    }
+}
@@ -1,20 +1,313 @@
-pub mod cpu;
-
-use cpu::Cpu;
+use rust_gb::apu::CLOCK_RATE;
+use rust_gb::gbs::{self, Gbs, GbsPlayer};
+use rust_gb::harness;
+use rust_gb::link::TcpLink;
+use rust_gb::printer::Printer;
+use rust_gb::trace_diff::{self, Comparison};
+use rust_gb::vgm::VgmRecorder;
+use rust_gb::wav::AudioRecorder;
+use rust_gb::{Cpu, GameBoy, Model, Renderer, RunStatus};
 use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Options that take the next argument as their value.
+const VALUE_OPTIONS: [&str; 18] = [
+    "--frames",
+    "--doctor-trace",
+    "--history",
+    "--log",
+    "--diff-trace",
+    "--diff-context",
+    "--screenshot-scale",
+    "--link-listen",
+    "--link-connect",
+    "--printer",
+    "--record-audio",
+    "--record-vgm",
+    "--vgm-loop",
+    "--tracks",
+    "--seconds",
+    "--output",
+    "--mute",
+    "--solo",
+];
+
+/// Options that take the next two arguments as their values.
+const PAIR_OPTIONS: [&str; 1] = ["--screenshot-at-frame"];
 
 fn main() {
     let args = env::args().skip(1).collect::<Vec<String>>();
-    if args.is_empty() {
+    init_logging(&args);
+    if args.first().is_some_and(|arg| arg == "play-gbs") {
+        play_gbs(&args[1..]);
+        return;
+    }
+
+    let Some(path) = rom_path(&args) else {
         println!("No ROM file specified");
         return;
+    };
+
+    let rom = std::fs::read(path).unwrap();
+    let frame_limit = option_value(&args, "--frames")
+        .map(|frames| frames.parse::<u64>().expect("Invalid --frames count"));
+    let headless = has_flag(&args, "--headless");
+    if headless && frame_limit.is_none() {
+        eprintln!("--headless needs --frames N");
+        process::exit(2);
+    }
+
+    let mooneye = has_flag(&args, "--mooneye");
+    let mut builder = GameBoy::builder()
+        .serial_echo(has_flag(&args, "--print-serial"))
+        .software_breakpoints(mooneye);
+    if has_flag(&args, "--fifo") {
+        builder = builder.renderer(Renderer::Fifo);
+    }
+    let mut gb = builder.build();
+    if let Err(err) = gb.load_cartridge(rom) {
+        eprintln!("Can't load {}: {}", path, err);
+        process::exit(2);
+    }
+    let cpu = gb.cpu_mut();
+
+    if let Some(addr) = option_value(&args, "--link-listen") {
+        println!("Waiting for link cable connection on {}", addr);
+        cpu.connect_serial(Box::new(TcpLink::listen(addr).unwrap()));
+    } else if let Some(addr) = option_value(&args, "--link-connect") {
+        cpu.connect_serial(Box::new(TcpLink::connect(addr).unwrap()));
+    } else if let Some(dir) = option_value(&args, "--printer") {
+        cpu.connect_serial(Box::new(Printer::new(dir)));
     }
 
-    let rom = std::fs::read(&args[0]).unwrap();
+    if let Some(len) = option_value(&args, "--history") {
+        cpu.set_history_len(len.parse().expect("Invalid --history length"));
+    }
+    if let Some(path) = option_value(&args, "--doctor-trace") {
+        let file = File::create(path).unwrap();
+        cpu.set_doctor_trace(Some(Box::new(BufWriter::new(file))));
+    }
 
-    let mut cpu = Cpu::new();
+    if let Some(list) = option_value(&args, "--mute") {
+        for channel in parse_channels(list) {
+            cpu.apu_mut().set_channel_muted(channel, true);
+        }
+    }
+    if let Some(list) = option_value(&args, "--solo") {
+        for channel in parse_channels(list) {
+            cpu.apu_mut().set_channel_solo(channel, true);
+        }
+    }
 
-    cpu.load_rom(rom);
+    let mut recorder = option_value(&args, "--record-audio").map(|path| {
+        let split = has_flag(&args, "--record-channels");
+        AudioRecorder::create(cpu, path, split).unwrap()
+    });
+    let mut vgm = option_value(&args, "--record-vgm").map(|path| {
+        let mut vgm = VgmRecorder::create(cpu, path).unwrap();
+        if let Some(seconds) = option_value(&args, "--vgm-loop") {
+            vgm.set_loop_at(seconds.parse().expect("Invalid --vgm-loop seconds"));
+        }
+        vgm
+    });
+    if let Some(path) = option_value(&args, "--diff-trace") {
+        let context = option_value(&args, "--diff-context").map_or(5, |context| {
+            context.parse().expect("Invalid --diff-context")
+        });
+        let reference = BufReader::new(File::open(path).unwrap());
+        let code = match trace_diff::find_divergence(&mut gb, reference, context) {
+            Ok(Comparison::Diverged(divergence)) => {
+                print!("{}", divergence);
+                1
+            }
+            Ok(Comparison::Matched { lines, skipped }) => {
+                println!("Matched all {} trace lines of {}", lines, path);
+                if skipped > 0 {
+                    println!("Skipped {} lines that aren't traces", skipped);
+                }
+                0
+            }
+            Err(err) => {
+                eprintln!("Can't compare with {}: {}", path, err);
+                2
+            }
+        };
+        drop(gb);
+        process::exit(code);
+    }
+
+    let screenshots = screenshot_requests(&args);
+    let scale = option_value(&args, "--screenshot-scale").map_or(1, |scale| {
+        scale.parse().expect("Invalid --screenshot-scale")
+    });
+    let audio_debug = has_flag(&args, "--audio-debug");
+    let mut next_report = 0;
+    let mut frames = 0;
+    // A frame at a time, so recording and reporting keep up. There's no
+    // input to wake a stopped CPU with, so that ends the run too.
+    let status = loop {
+        if frame_limit.is_some_and(|limit| frames >= limit) {
+            break RunStatus::Finished;
+        }
+        let status = gb.run_frame();
+        if status != RunStatus::Finished {
+            break status;
+        }
+        frames += 1;
+        for (_, path) in screenshots.iter().filter(|(frame, _)| *frame == frames) {
+            gb.save_screenshot(path, scale).unwrap();
+        }
+
+        let cpu = gb.cpu_mut();
+        if let Some(recorder) = &mut recorder {
+            recorder.record(cpu).unwrap();
+        }
+        if let Some(vgm) = &mut vgm {
+            vgm.record(cpu).unwrap();
+        }
+        if audio_debug && cpu.cycles() >= next_report {
+            next_report += CLOCK_RATE as u64 / 4;
+            print_audio_state(cpu);
+        }
+    };
+
+    if headless {
+        println!(
+            "Ran {} frames ({} cycles): {:?}",
+            frames,
+            gb.cycles(),
+            status
+        );
+    }
+    let code = match status {
+        RunStatus::Finished => 0,
+        RunStatus::Stopped => {
+            if !headless {
+                println!("Stopped with STOP and nothing to press a button");
+            }
+            1
+        }
+        RunStatus::Breakpoint => {
+            let outcome = harness::mooneye_outcome(gb.cpu().registers());
+            println!("Mooneye test: {}", outcome);
+            if outcome.passed() {
+                0
+            } else {
+                1
+            }
+        }
+    };
+    // Exiting skips destructors, so the trace has to be flushed first.
+    drop(gb);
+    process::exit(code);
+}
 
-    cpu.run();
+/// Sets up logging from `--log`, or `RUST_GB_LOG`, in `env_logger` syntax:
+/// a default level and levels per category, like `warn,cpu=trace,ppu=debug`.
+/// The categories are cpu, mmu, ppu, apu, timer, serial and interrupts.
+fn init_logging(args: &[String]) {
+    let spec = option_value(args, "--log")
+        .map(str::to_string)
+        .or_else(|| env::var("RUST_GB_LOG").ok())
+        .unwrap_or_else(|| "warn".to_string());
+    env_logger::Builder::new().parse_filters(&spec).init();
+}
+
+/// Channel numbers as written on the command line, 1-4, to indices.
+fn parse_channels(list: &str) -> Vec<usize> {
+    list.split(',')
+        .map(|channel| match channel.trim().parse::<usize>() {
+            Ok(channel @ 1..=4) => channel - 1,
+            _ => panic!("Invalid audio channel: {}", channel),
+        })
+        .collect()
+}
+
+fn print_audio_state(cpu: &Cpu) {
+    for (i, state) in cpu.apu().channel_states().iter().enumerate() {
+        println!("CH{} {}", i + 1, state);
+    }
+    if cpu.model() == Model::Cgb {
+        println!(
+            "PCM12={:02X} PCM34={:02X}",
+            cpu.apu().read_register(0xff76),
+            cpu.apu().read_register(0xff77)
+        );
+    }
+}
+
+/// `play-gbs FILE [--tracks 1,2,...] [--seconds N] [--output out.wav]`
+/// renders each track to its own WAV file.
+fn play_gbs(args: &[String]) {
+    let Some(path) = rom_path(args) else {
+        println!("No GBS file specified");
+        return;
+    };
+    let gbs = Gbs::open(path).unwrap();
+    println!("{} - {} ({} songs)", gbs.title, gbs.author, gbs.songs);
+
+    let tracks = match option_value(args, "--tracks") {
+        Some(list) => list
+            .split(',')
+            .map(|track| track.trim().parse().unwrap())
+            .collect(),
+        None => vec![gbs.first_song],
+    };
+    let seconds = option_value(args, "--seconds").map_or(60, |value| value.parse().unwrap());
+    let output = option_value(args, "--output")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| Path::new(path).with_extension("wav"));
+
+    let mut player = GbsPlayer::new(gbs);
+    for &track in &tracks {
+        let path = if tracks.len() == 1 {
+            output.clone()
+        } else {
+            let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+            output.with_file_name(format!("{}-{:02}.wav", stem, track))
+        };
+        println!("Rendering track {} to {}", track, path.display());
+        gbs::render_track(&mut player, track, seconds, &path).unwrap();
+    }
+}
+
+/// Every `--screenshot-at-frame N path.png` as (N, path).
+fn screenshot_requests(args: &[String]) -> Vec<(u64, PathBuf)> {
+    args.windows(3)
+        .filter(|window| window[0] == "--screenshot-at-frame")
+        .map(|window| {
+            let frame = window[1]
+                .parse()
+                .expect("Invalid --screenshot-at-frame frame");
+            (frame, PathBuf::from(&window[2]))
+        })
+        .collect()
+}
+
+fn has_flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|arg| arg == name)
+}
+
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    let index = args.iter().position(|arg| arg == name)?;
+    args.get(index + 1).map(|value| value.as_str())
+}
+
+/// The first argument that is neither an option nor an option's value.
+fn rom_path(args: &[String]) -> Option<&String> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        } else if PAIR_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+            args.next();
+        } else if !arg.starts_with("--") {
+            return Some(arg);
+        }
+    }
+    None
 }
@@ -0,0 +1,692 @@
+mod fifo;
+mod scanline;
+
+use crate::interrupt;
+use fifo::Fifo;
+
+// https://gbdev.io/pandocs/Rendering.html
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const SCANLINE_DRAWING_DOTS: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+// https://gbdev.io/pandocs/LCDC.html
+pub const LCDC_BG_ENABLE: u8 = 0b0000_0001;
+pub const LCDC_OBJ_ENABLE: u8 = 0b0000_0010;
+pub const LCDC_OBJ_SIZE: u8 = 0b0000_0100;
+pub const LCDC_BG_MAP: u8 = 0b0000_1000;
+pub const LCDC_TILE_DATA: u8 = 0b0001_0000;
+pub const LCDC_WINDOW_ENABLE: u8 = 0b0010_0000;
+pub const LCDC_WINDOW_MAP: u8 = 0b0100_0000;
+pub const LCDC_LCD_ENABLE: u8 = 0b1000_0000;
+
+// https://gbdev.io/pandocs/STAT.html
+const STAT_LYC_EQUAL: u8 = 0b0000_0100;
+const STAT_HBLANK_INT: u8 = 0b0000_1000;
+const STAT_VBLANK_INT: u8 = 0b0001_0000;
+const STAT_OAM_INT: u8 = 0b0010_0000;
+const STAT_LYC_INT: u8 = 0b0100_0000;
+
+// https://gbdev.io/pandocs/OAM.html
+const OBJ_BG_PRIORITY: u8 = 0b1000_0000;
+const OBJ_Y_FLIP: u8 = 0b0100_0000;
+const OBJ_X_FLIP: u8 = 0b0010_0000;
+const OBJ_PALETTE: u8 = 0b0001_0000;
+
+// https://gbdev.io/pandocs/Tile_Maps.html#bg-map-attributes-cgb-mode-only
+const BG_PRIORITY: u8 = 0b1000_0000;
+const BG_Y_FLIP: u8 = 0b0100_0000;
+const BG_X_FLIP: u8 = 0b0010_0000;
+
+// Shared by BG map attributes and OAM flags in CGB mode.
+const CGB_VRAM_BANK: u8 = 0b0000_1000;
+const CGB_PALETTE: u8 = 0b0000_0111;
+
+// https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+const PALETTE_AUTO_INCREMENT: u8 = 0b1000_0000;
+const PALETTE_INDEX: u8 = 0b0011_1111;
+
+/// RGB555 colours of the four DMG shades, lightest first.
+pub const DMG_COLORS: [u16; 4] = [0x7fff, 0x56b5, 0x294a, 0x0000];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+/// Selects how mode 3 is emulated.
+///
+/// `Scanline` draws a whole line at once at the end of a fixed-length mode 3
+/// and is the fast default. `Fifo` runs the pixel pipeline dot by dot, so
+/// register writes in the middle of a line show up where they happened.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Renderer {
+    Scanline,
+    Fifo,
+}
+
+#[derive(Clone, Copy)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    flags: u8,
+    index: u8,
+}
+
+/// A BG or window pixel before palette lookup. `attrs` are the BG map
+/// attributes, always 0 outside CGB mode.
+#[derive(Clone, Copy)]
+struct BgPixel {
+    color: u8,
+    attrs: u8,
+}
+
+/// A sprite pixel before palette lookup, with the OAM index of its sprite.
+#[derive(Clone, Copy)]
+struct ObjPixel {
+    color: u8,
+    flags: u8,
+    index: u8,
+}
+
+pub struct Ppu {
+    cgb: bool,
+    vram: [u8; 0x4000],
+    vbk: u8,
+    oam: [u8; 0xa0],
+    lcdc: u8,
+    stat: u8,
+    scy: u8,
+    scx: u8,
+    ly: u8,
+    lyc: u8,
+    bgp: u8,
+    obp0: u8,
+    obp1: u8,
+    wy: u8,
+    wx: u8,
+    bg_palettes: [u8; 64],
+    obj_palettes: [u8; 64],
+    bcps: u8,
+    ocps: u8,
+    opri: u8,
+    mode: Mode,
+    dot: u16,
+    renderer: Renderer,
+    sprites: Vec<Sprite>,
+    window_line: u8,
+    window_drawn: bool,
+    wy_triggered: bool,
+    fifo: Fifo,
+    framebuffer: Vec<u16>,
+    frames: u64,
+    stat_line: bool,
+    interrupts: u8,
+    hblank_started: bool,
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Self {
+            cgb: false,
+            vram: [0; 0x4000],
+            vbk: 0,
+            oam: [0; 0xa0],
+            lcdc: 0x91,
+            stat: 0x00,
+            scy: 0x00,
+            scx: 0x00,
+            ly: 0x00,
+            lyc: 0x00,
+            bgp: 0xfc,
+            obp0: 0xff,
+            obp1: 0xff,
+            wy: 0x00,
+            wx: 0x00,
+            bg_palettes: [0xff; 64],
+            obj_palettes: [0xff; 64],
+            bcps: 0x00,
+            ocps: 0x00,
+            opri: 0x00,
+            mode: Mode::OamScan,
+            dot: 0,
+            renderer: Renderer::Scanline,
+            sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            window_line: 0,
+            window_drawn: false,
+            wy_triggered: false,
+            fifo: Fifo::new(),
+            framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frames: 0,
+            stat_line: false,
+            interrupts: 0,
+            hblank_started: false,
+        }
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    /// Enables the CGB-only registers, VRAM bank 1 and colour palettes.
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    /// RGB555 colours of the last drawn frame, row by row.
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    /// Frames drawn to the end since power on, counted as VBlank starts.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Returns and clears the interrupt flags requested since the last call.
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    /// Returns whether a visible line entered HBlank since the last call.
+    pub fn take_hblank(&mut self) -> bool {
+        std::mem::take(&mut self.hblank_started)
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        self.lcdc & LCDC_LCD_ENABLE != 0
+    }
+
+    pub fn read_vram(&self, addr: u16) -> u8 {
+        self.vram[self.vbk as usize * 0x2000 + (addr & 0x1fff) as usize]
+    }
+
+    pub fn write_vram(&mut self, addr: u16, byte: u8) {
+        self.vram[self.vbk as usize * 0x2000 + (addr & 0x1fff) as usize] = byte;
+    }
+
+    pub fn read_oam(&self, addr: u16) -> u8 {
+        self.oam[(addr - 0xfe00) as usize]
+    }
+
+    pub fn write_oam(&mut self, addr: u16, byte: u8) {
+        self.oam[(addr - 0xfe00) as usize] = byte;
+    }
+
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff40 => self.lcdc,
+            0xff41 => 0x80 | self.stat | self.mode as u8,
+            0xff42 => self.scy,
+            0xff43 => self.scx,
+            0xff44 => self.ly,
+            0xff45 => self.lyc,
+            0xff47 => self.bgp,
+            0xff48 => self.obp0,
+            0xff49 => self.obp1,
+            0xff4a => self.wy,
+            0xff4b => self.wx,
+            0xff4f if self.cgb => 0xfe | self.vbk,
+            0xff68 if self.cgb => 0x40 | self.bcps,
+            0xff69 if self.cgb => self.bg_palettes[(self.bcps & PALETTE_INDEX) as usize],
+            0xff6a if self.cgb => 0x40 | self.ocps,
+            0xff6b if self.cgb => self.obj_palettes[(self.ocps & PALETTE_INDEX) as usize],
+            0xff6c if self.cgb => 0xfe | self.opri,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0xff40 => {
+                let was_enabled = self.lcdc & LCDC_LCD_ENABLE != 0;
+                self.lcdc = byte;
+                if was_enabled && byte & LCDC_LCD_ENABLE == 0 {
+                    log::debug!(target: "ppu", "LCD off");
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.mode = Mode::HBlank;
+                } else if !was_enabled && byte & LCDC_LCD_ENABLE != 0 {
+                    log::debug!(target: "ppu", "LCD on");
+                    self.start_frame();
+                }
+            }
+            0xff41 => self.stat = (self.stat & STAT_LYC_EQUAL) | (byte & 0x78),
+            0xff42 => self.scy = byte,
+            0xff43 => self.scx = byte,
+            0xff44 => {}
+            0xff45 => self.lyc = byte,
+            0xff47 => self.bgp = byte,
+            0xff48 => self.obp0 = byte,
+            0xff49 => self.obp1 = byte,
+            0xff4a => self.wy = byte,
+            0xff4b => self.wx = byte,
+            0xff4f if self.cgb => self.vbk = byte & 0x01,
+            0xff68 if self.cgb => self.bcps = byte & (PALETTE_AUTO_INCREMENT | PALETTE_INDEX),
+            0xff69 if self.cgb => write_palette(&mut self.bg_palettes, &mut self.bcps, byte),
+            0xff6a if self.cgb => self.ocps = byte & (PALETTE_AUTO_INCREMENT | PALETTE_INDEX),
+            0xff6b if self.cgb => write_palette(&mut self.obj_palettes, &mut self.ocps, byte),
+            0xff6c if self.cgb => self.opri = byte & 0x01,
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self, dots: u32) {
+        if self.lcdc & LCDC_LCD_ENABLE == 0 {
+            return;
+        }
+        for _ in 0..dots {
+            self.step_dot();
+        }
+    }
+
+    fn step_dot(&mut self) {
+        match self.mode {
+            Mode::OamScan => {
+                if self.dot == 0 {
+                    self.scan_oam();
+                }
+                if self.dot == OAM_SCAN_DOTS - 1 {
+                    self.enter_drawing();
+                }
+            }
+            Mode::Drawing => match self.renderer {
+                Renderer::Scanline => {
+                    if self.dot == OAM_SCAN_DOTS + SCANLINE_DRAWING_DOTS - 1 {
+                        self.render_scanline();
+                        self.set_mode(Mode::HBlank);
+                    }
+                }
+                Renderer::Fifo => {
+                    if self.fifo_step() {
+                        self.set_mode(Mode::HBlank);
+                    }
+                }
+            },
+            Mode::HBlank | Mode::VBlank => {}
+        }
+
+        self.dot += 1;
+        if self.dot == DOTS_PER_LINE {
+            self.dot = 0;
+            self.next_line();
+        }
+        self.update_stat_line();
+    }
+
+    fn next_line(&mut self) {
+        if self.window_drawn {
+            self.window_line += 1;
+            self.window_drawn = false;
+        }
+        self.ly += 1;
+        if self.ly == SCREEN_HEIGHT as u8 {
+            self.set_mode(Mode::VBlank);
+            self.interrupts |= interrupt::VBLANK;
+            self.frames += 1;
+            log::trace!(target: "ppu", "Frame {} drawn", self.frames);
+        } else if self.ly == LINES_PER_FRAME {
+            self.start_frame();
+        } else if self.ly < SCREEN_HEIGHT as u8 {
+            self.set_mode(Mode::OamScan);
+        }
+    }
+
+    fn start_frame(&mut self) {
+        self.ly = 0;
+        self.dot = 0;
+        self.window_line = 0;
+        self.window_drawn = false;
+        self.wy_triggered = false;
+        self.set_mode(Mode::OamScan);
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+        match mode {
+            Mode::OamScan if self.ly == self.wy => self.wy_triggered = true,
+            Mode::HBlank => self.hblank_started = true,
+            _ => {}
+        }
+    }
+
+    fn enter_drawing(&mut self) {
+        self.set_mode(Mode::Drawing);
+        if self.renderer == Renderer::Fifo {
+            self.fifo.start_line(self.scx);
+        }
+    }
+
+    fn update_stat_line(&mut self) {
+        let lyc_equal = self.ly == self.lyc;
+        if lyc_equal {
+            self.stat |= STAT_LYC_EQUAL;
+        } else {
+            self.stat &= !STAT_LYC_EQUAL;
+        }
+
+        let line = (lyc_equal && self.stat & STAT_LYC_INT != 0)
+            || match self.mode {
+                Mode::HBlank => self.stat & STAT_HBLANK_INT != 0,
+                Mode::VBlank => self.stat & STAT_VBLANK_INT != 0,
+                Mode::OamScan => self.stat & STAT_OAM_INT != 0,
+                Mode::Drawing => false,
+            };
+        if line && !self.stat_line {
+            self.interrupts |= interrupt::LCD_STAT;
+        }
+        self.stat_line = line;
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & LCDC_OBJ_SIZE != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn scan_oam(&mut self) {
+        let height = self.sprite_height();
+        let line = self.ly as u16 + 16;
+        self.sprites.clear();
+        for (index, entry) in self.oam.chunks_exact(4).enumerate() {
+            let y = entry[0] as u16;
+            if line >= y && line < y + height as u16 {
+                self.sprites.push(Sprite {
+                    y: entry[0],
+                    x: entry[1],
+                    tile: entry[2],
+                    flags: entry[3],
+                    index: index as u8,
+                });
+                if self.sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn window_visible(&self) -> bool {
+        self.lcdc & LCDC_WINDOW_ENABLE != 0 && self.wy_triggered && self.wx <= 166
+    }
+
+    /// Sprites are drawn in X order on DMG and when OPRI bit 0 is set,
+    /// otherwise in OAM order.
+    fn obj_priority_by_x(&self) -> bool {
+        !self.cgb || self.opri & 0x01 != 0
+    }
+
+    /// Returns the tile number and, in CGB mode, the attributes of a map entry.
+    fn tile_map_entry(&self, map_flag: u8, tile_x: u8, tile_y: u8) -> (u8, u8) {
+        let base = if self.lcdc & map_flag != 0 {
+            0x1c00
+        } else {
+            0x1800
+        };
+        let offset = base + tile_y as usize * 32 + tile_x as usize;
+        let attrs = if self.cgb {
+            self.vram[0x2000 + offset]
+        } else {
+            0
+        };
+        (self.vram[offset], attrs)
+    }
+
+    /// Returns the two bit planes of one row of a BG/window tile, already
+    /// mirrored for X flip.
+    fn bg_tile_row(&self, tile: u8, row: u8, attrs: u8) -> (u8, u8) {
+        let row = if attrs & BG_Y_FLIP != 0 { 7 - row } else { row };
+        let bank = if attrs & CGB_VRAM_BANK != 0 {
+            0x2000
+        } else {
+            0
+        };
+        let addr =
+            bank + if self.lcdc & LCDC_TILE_DATA != 0 {
+                tile as usize * 16
+            } else {
+                (0x1000 + (tile as i8 as i32) * 16) as usize
+            } + row as usize * 2;
+        let (lo, hi) = (self.vram[addr], self.vram[addr + 1]);
+        if attrs & BG_X_FLIP != 0 {
+            (lo.reverse_bits(), hi.reverse_bits())
+        } else {
+            (lo, hi)
+        }
+    }
+
+    /// Returns the two bit planes of the row of `sprite` that covers the
+    /// current line, already mirrored for X flip.
+    fn sprite_tile_row(&self, sprite: &Sprite) -> (u8, u8) {
+        let height = self.sprite_height();
+        // The sprite was picked with the height at OAM scan; if LCDC has
+        // switched it to 8 pixels since, the row wraps within the tile.
+        let mut row = (self.ly + 16 - sprite.y) & (height - 1);
+        if sprite.flags & OBJ_Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+        let tile = if height == 16 {
+            sprite.tile & 0xfe
+        } else {
+            sprite.tile
+        };
+        let bank = if self.cgb && sprite.flags & CGB_VRAM_BANK != 0 {
+            0x2000
+        } else {
+            0
+        };
+        let addr = bank + tile as usize * 16 + row as usize * 2;
+        let (lo, hi) = (self.vram[addr], self.vram[addr + 1]);
+        if sprite.flags & OBJ_X_FLIP != 0 {
+            (lo.reverse_bits(), hi.reverse_bits())
+        } else {
+            (lo, hi)
+        }
+    }
+
+    /// Resolves BG/sprite priority for one pixel and returns its RGB555
+    /// colour.
+    fn mix_pixel(&self, bg: BgPixel, obj: Option<ObjPixel>) -> u16 {
+        let obj = obj.filter(|obj| obj.color != 0 && self.lcdc & LCDC_OBJ_ENABLE != 0);
+
+        if self.cgb {
+            // In CGB mode LCDC bit 0 doesn't hide the BG, it only takes away
+            // its priority over sprites.
+            if let Some(obj) = obj {
+                let bg_on_top = self.lcdc & LCDC_BG_ENABLE != 0
+                    && bg.color != 0
+                    && (bg.attrs & BG_PRIORITY != 0 || obj.flags & OBJ_BG_PRIORITY != 0);
+                if !bg_on_top {
+                    return cgb_color(&self.obj_palettes, obj.flags & CGB_PALETTE, obj.color);
+                }
+            }
+            return cgb_color(&self.bg_palettes, bg.attrs & CGB_PALETTE, bg.color);
+        }
+
+        let bg_color = if self.lcdc & LCDC_BG_ENABLE != 0 {
+            bg.color
+        } else {
+            0
+        };
+        if let Some(obj) = obj {
+            if obj.flags & OBJ_BG_PRIORITY == 0 || bg_color == 0 {
+                let palette = if obj.flags & OBJ_PALETTE != 0 {
+                    self.obp1
+                } else {
+                    self.obp0
+                };
+                return DMG_COLORS[apply_palette(palette, obj.color) as usize];
+            }
+        }
+        DMG_COLORS[apply_palette(self.bgp, bg_color) as usize]
+    }
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn apply_palette(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
+
+/// Writes BCPD/OCPD and advances the index in BCPS/OCPS if auto-increment
+/// is set.
+fn write_palette(ram: &mut [u8; 64], spec: &mut u8, byte: u8) {
+    ram[(*spec & PALETTE_INDEX) as usize] = byte;
+    if *spec & PALETTE_AUTO_INCREMENT != 0 {
+        *spec = PALETTE_AUTO_INCREMENT | ((*spec + 1) & PALETTE_INDEX);
+    }
+}
+
+fn cgb_color(ram: &[u8; 64], palette: u8, color: u8) -> u16 {
+    let offset = palette as usize * 8 + color as usize * 2;
+    u16::from_le_bytes([ram[offset], ram[offset + 1]]) & 0x7fff
+}
+
+fn color_id(lo: u8, hi: u8, bit: u8) -> u8 {
+    (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drawing_dots(ppu: &mut Ppu) -> u16 {
+        while ppu.mode != Mode::Drawing {
+            ppu.tick(1);
+        }
+        let mut dots = 0;
+        while ppu.mode == Mode::Drawing {
+            ppu.tick(1);
+            dots += 1;
+        }
+        dots
+    }
+
+    fn checkerboard_ppu(renderer: Renderer, cgb: bool) -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.set_renderer(renderer);
+        ppu.set_cgb_mode(cgb);
+        if cgb {
+            ppu.write_register(0xff68, PALETTE_AUTO_INCREMENT);
+            for i in 0..64u8 {
+                ppu.write_register(0xff69, i.wrapping_mul(37));
+            }
+            ppu.write_register(0xff6a, PALETTE_AUTO_INCREMENT);
+            for i in 0..64u8 {
+                ppu.write_register(0xff6b, i.wrapping_mul(11));
+            }
+            ppu.write_register(0xff4f, 1);
+            for i in 0..0x400 {
+                ppu.write_vram(0x9800 + i, (i % 7) as u8 & (CGB_PALETTE | BG_X_FLIP));
+            }
+            ppu.write_register(0xff4f, 0);
+        }
+        for row in 0..8 {
+            ppu.write_vram(0x8010 + row * 2, 0b1010_1010);
+            ppu.write_vram(0x8011 + row * 2, 0b0110_0110);
+        }
+        for i in 0..0x400 {
+            ppu.write_vram(0x9800 + i, (i % 2) as u8);
+        }
+        ppu.write_oam(0xfe00, 20);
+        ppu.write_oam(0xfe01, 13);
+        ppu.write_oam(0xfe02, 1);
+        ppu.write_oam(0xfe03, OBJ_X_FLIP | 0x02);
+        ppu.write_oam(0xfe04, 22);
+        ppu.write_oam(0xfe05, 9);
+        ppu.write_oam(0xfe06, 1);
+        ppu.write_oam(0xfe07, OBJ_PALETTE | 0x05);
+        ppu.write_register(0xff43, 5);
+        ppu.write_register(0xff40, 0x93);
+        ppu
+    }
+
+    #[test]
+    fn test_renderers_draw_same_frame() {
+        for cgb in [false, true] {
+            let mut scanline = checkerboard_ppu(Renderer::Scanline, cgb);
+            let mut fifo = checkerboard_ppu(Renderer::Fifo, cgb);
+            scanline.tick(DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32);
+            fifo.tick(DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32);
+            assert!(scanline.framebuffer().iter().any(|&color| color != 0x7fff));
+            assert_eq!(scanline.framebuffer(), fifo.framebuffer());
+        }
+    }
+
+    #[test]
+    fn test_sprite_height_change_after_scan() {
+        let mut ppu = Ppu::new();
+        ppu.ly = 12;
+        let sprite = Sprite {
+            y: 16,
+            x: 8,
+            tile: 1,
+            flags: OBJ_Y_FLIP,
+            index: 0,
+        };
+        // Row 12 of a 16-pixel sprite is row 3 of the flipped 8-pixel one.
+        ppu.vram[16 + 3 * 2] = 0xaa;
+        assert_eq!(ppu.sprite_tile_row(&sprite), (0xaa, 0));
+    }
+
+    #[test]
+    fn test_cgb_palette_auto_increment() {
+        let mut ppu = Ppu::new();
+        ppu.set_cgb_mode(true);
+        ppu.write_register(0xff68, PALETTE_AUTO_INCREMENT | 0x3e);
+        ppu.write_register(0xff69, 0x1f);
+        ppu.write_register(0xff69, 0x00);
+        ppu.write_register(0xff69, 0xe0);
+        assert_eq!(
+            ppu.read_register(0xff68),
+            0x40 | PALETTE_AUTO_INCREMENT | 0x01
+        );
+        assert_eq!(cgb_color(&ppu.bg_palettes, 7, 3), 0x001f);
+        assert_eq!(cgb_color(&ppu.bg_palettes, 0, 0), 0x7fe0);
+    }
+
+    #[test]
+    fn test_fifo_mode3_length() {
+        let mut ppu = Ppu::new();
+        ppu.set_renderer(Renderer::Fifo);
+        assert_eq!(drawing_dots(&mut ppu), 172);
+
+        ppu.write_register(0xff43, 3);
+        assert_eq!(drawing_dots(&mut ppu), 175);
+
+        ppu.write_register(0xff43, 0);
+        ppu.write_register(0xff40, 0x93);
+        ppu.write_oam(0xfe00, 16 + 2);
+        ppu.write_oam(0xfe01, 8 + 80);
+        assert!(drawing_dots(&mut ppu) > 172 + 6);
+    }
+
+    #[test]
+    fn test_fifo_mid_line_palette_write() {
+        let mut ppu = Ppu::new();
+        ppu.set_renderer(Renderer::Fifo);
+        ppu.write_register(0xff47, 0x00);
+        while ppu.mode != Mode::Drawing {
+            ppu.tick(1);
+        }
+        ppu.tick(12 + 80);
+        ppu.write_register(0xff47, 0xff);
+        ppu.tick(DOTS_PER_LINE as u32);
+        let line = &ppu.framebuffer()[..SCREEN_WIDTH];
+        assert_eq!(line[0], DMG_COLORS[0]);
+        assert_eq!(line[SCREEN_WIDTH - 1], DMG_COLORS[3]);
+    }
+}
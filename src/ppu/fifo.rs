@@ -0,0 +1,253 @@
+// https://gbdev.io/pandocs/pixel_fifo.html
+use std::collections::VecDeque;
+
+use super::{
+    color_id, BgPixel, ObjPixel, Ppu, LCDC_BG_MAP, LCDC_OBJ_ENABLE, LCDC_WINDOW_MAP, SCREEN_WIDTH,
+};
+
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FetchStep {
+    GetTile,
+    GetDataLow,
+    GetDataHigh,
+    Push,
+}
+
+struct Fetcher {
+    step: FetchStep,
+    dots: u8,
+    tile_x: u8,
+    window: bool,
+    first_fetch: bool,
+    tile: u8,
+    attrs: u8,
+    lo: u8,
+    hi: u8,
+}
+
+pub(super) struct Fifo {
+    fetcher: Fetcher,
+    bg: VecDeque<BgPixel>,
+    obj: VecDeque<ObjPixel>,
+    lx: u8,
+    discard: u8,
+    sprite_dots: u8,
+    sprite: usize,
+    fetched_sprites: u16,
+}
+
+impl Fetcher {
+    fn new() -> Self {
+        Self {
+            step: FetchStep::GetTile,
+            dots: 0,
+            tile_x: 0,
+            window: false,
+            first_fetch: true,
+            tile: 0,
+            attrs: 0,
+            lo: 0,
+            hi: 0,
+        }
+    }
+}
+
+impl Fifo {
+    pub(super) fn new() -> Self {
+        Self {
+            fetcher: Fetcher::new(),
+            bg: VecDeque::with_capacity(16),
+            obj: VecDeque::with_capacity(8),
+            lx: 0,
+            discard: 0,
+            sprite_dots: 0,
+            sprite: 0,
+            fetched_sprites: 0,
+        }
+    }
+
+    pub(super) fn start_line(&mut self, scx: u8) {
+        *self = Self::new();
+        self.discard = scx % 8;
+    }
+}
+
+impl Ppu {
+    /// Advances the pixel pipeline by one dot. Returns true once the 160th
+    /// pixel of the line has been pushed to the LCD.
+    pub(super) fn fifo_step(&mut self) -> bool {
+        if self.fifo.sprite_dots > 0 {
+            self.fifo.sprite_dots -= 1;
+            if self.fifo.sprite_dots == 0 {
+                self.fetch_sprite();
+            }
+            return false;
+        }
+
+        self.fetcher_step();
+
+        if !self.fifo.fetcher.window && self.window_visible() {
+            let lx = self.fifo.lx as u16;
+            if lx + 7 >= self.wx as u16 {
+                self.start_window();
+                return false;
+            }
+        }
+
+        if self.fifo.bg.is_empty() {
+            return false;
+        }
+
+        if self.fifo.discard > 0 {
+            self.fifo.bg.pop_front();
+            self.fifo.discard -= 1;
+            return false;
+        }
+
+        if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            if let Some(index) = self.next_sprite() {
+                // The sprite fetch only begins once the BG fetcher has a
+                // tile ready; until then it keeps running and nothing is
+                // shifted out.
+                if self.fifo.fetcher.step == FetchStep::Push {
+                    self.fifo.sprite = index;
+                    self.fifo.sprite_dots = SPRITE_FETCH_DOTS - 1;
+                }
+                return false;
+            }
+        }
+
+        let bg = self.fifo.bg.pop_front().unwrap();
+        let obj = self.fifo.obj.pop_front();
+        let color = self.mix_pixel(bg, obj);
+        self.framebuffer[self.ly as usize * SCREEN_WIDTH + self.fifo.lx as usize] = color;
+        self.fifo.lx += 1;
+
+        self.fifo.lx == SCREEN_WIDTH as u8
+    }
+
+    fn fetcher_step(&mut self) {
+        let step = self.fifo.fetcher.step;
+        if step == FetchStep::Push {
+            if self.fifo.bg.is_empty() {
+                let fetcher = &mut self.fifo.fetcher;
+                for bit in (0..8).rev() {
+                    self.fifo.bg.push_back(BgPixel {
+                        color: color_id(fetcher.lo, fetcher.hi, bit),
+                        attrs: fetcher.attrs,
+                    });
+                }
+                fetcher.tile_x = fetcher.tile_x.wrapping_add(1);
+                fetcher.step = FetchStep::GetTile;
+            }
+            return;
+        }
+
+        self.fifo.fetcher.dots += 1;
+        if self.fifo.fetcher.dots < 2 {
+            return;
+        }
+        self.fifo.fetcher.dots = 0;
+
+        let (row, next) = match step {
+            FetchStep::GetTile => {
+                let (map_flag, tile_x, tile_y) = if self.fifo.fetcher.window {
+                    (
+                        LCDC_WINDOW_MAP,
+                        self.fifo.fetcher.tile_x,
+                        self.window_line / 8,
+                    )
+                } else {
+                    (
+                        LCDC_BG_MAP,
+                        (self.scx / 8).wrapping_add(self.fifo.fetcher.tile_x) & 0x1f,
+                        self.scy.wrapping_add(self.ly) / 8,
+                    )
+                };
+                let (tile, attrs) = self.tile_map_entry(map_flag, tile_x & 0x1f, tile_y);
+                self.fifo.fetcher.tile = tile;
+                self.fifo.fetcher.attrs = attrs;
+                (None, FetchStep::GetDataLow)
+            }
+            FetchStep::GetDataLow => (Some(false), FetchStep::GetDataHigh),
+            FetchStep::GetDataHigh => (Some(true), FetchStep::Push),
+            FetchStep::Push => unreachable!(),
+        };
+
+        if let Some(high) = row {
+            let row = if self.fifo.fetcher.window {
+                self.window_line % 8
+            } else {
+                self.scy.wrapping_add(self.ly) % 8
+            };
+            let (lo, hi) = self.bg_tile_row(self.fifo.fetcher.tile, row, self.fifo.fetcher.attrs);
+            if high {
+                self.fifo.fetcher.hi = hi;
+            } else {
+                self.fifo.fetcher.lo = lo;
+            }
+        }
+
+        self.fifo.fetcher.step = next;
+        // The very first fetch of a line is thrown away and done again.
+        if next == FetchStep::Push && self.fifo.fetcher.first_fetch {
+            self.fifo.fetcher.first_fetch = false;
+            self.fifo.fetcher.step = FetchStep::GetTile;
+        }
+    }
+
+    fn start_window(&mut self) {
+        self.window_drawn = true;
+        self.fifo.bg.clear();
+        self.fifo.discard = 7u8.saturating_sub(self.wx);
+        self.fifo.fetcher = Fetcher {
+            window: true,
+            first_fetch: false,
+            ..Fetcher::new()
+        };
+    }
+
+    /// Finds the leftmost sprite that starts at or before the current pixel
+    /// and has not been fetched yet.
+    fn next_sprite(&self) -> Option<usize> {
+        self.sprites
+            .iter()
+            .enumerate()
+            .filter(|(i, sprite)| {
+                self.fifo.fetched_sprites & (1 << i) == 0 && sprite.x <= self.fifo.lx + 8
+            })
+            .min_by_key(|(_, sprite)| sprite.x)
+            .map(|(i, _)| i)
+    }
+
+    fn fetch_sprite(&mut self) {
+        let index = self.fifo.sprite;
+        let sprite = self.sprites[index];
+        self.fifo.fetched_sprites |= 1 << index;
+
+        let (lo, hi) = self.sprite_tile_row(&sprite);
+        let by_x = self.obj_priority_by_x();
+        // Pixels left of the screen edge are dropped.
+        let skip = (self.fifo.lx as i16 + 8 - sprite.x as i16).max(0) as u8;
+        for i in skip..8 {
+            let pixel = ObjPixel {
+                color: color_id(lo, hi, 7 - i),
+                flags: sprite.flags,
+                index: sprite.index,
+            };
+            let slot = (i - skip) as usize;
+            if slot < self.fifo.obj.len() {
+                // Sprites are fetched in X order, so an earlier one only
+                // loses its pixel to a lower OAM index in CGB priority mode.
+                let old = self.fifo.obj[slot];
+                if old.color == 0 || (!by_x && pixel.color != 0 && pixel.index < old.index) {
+                    self.fifo.obj[slot] = pixel;
+                }
+            } else {
+                self.fifo.obj.push_back(pixel);
+            }
+        }
+    }
+}
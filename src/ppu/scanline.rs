@@ -0,0 +1,68 @@
+use super::{
+    color_id, BgPixel, ObjPixel, Ppu, LCDC_BG_MAP, LCDC_WINDOW_MAP, MAX_SPRITES_PER_LINE,
+    SCREEN_WIDTH,
+};
+
+impl Ppu {
+    /// Draws the whole current line from the register values at the end of
+    /// mode 3.
+    pub(super) fn render_scanline(&mut self) {
+        let mut bg_line = [BgPixel { color: 0, attrs: 0 }; SCREEN_WIDTH];
+        let window_x = self.wx as i16 - 7;
+        let window = self.window_visible();
+
+        for (x, bg) in bg_line.iter_mut().enumerate() {
+            let (map_flag, px, py) = if window && x as i16 >= window_x {
+                self.window_drawn = true;
+                (
+                    LCDC_WINDOW_MAP,
+                    (x as i16 - window_x) as u8,
+                    self.window_line,
+                )
+            } else {
+                (
+                    LCDC_BG_MAP,
+                    self.scx.wrapping_add(x as u8),
+                    self.scy.wrapping_add(self.ly),
+                )
+            };
+            let (tile, attrs) = self.tile_map_entry(map_flag, px / 8, py / 8);
+            let (lo, hi) = self.bg_tile_row(tile, py % 8, attrs);
+            *bg = BgPixel {
+                color: color_id(lo, hi, 7 - px % 8),
+                attrs,
+            };
+        }
+
+        // With X priority lower X wins, then lower OAM index, which is the
+        // order of a stable sort by X over the OAM-ordered selection.
+        let mut sprites = self.sprites.clone();
+        if self.obj_priority_by_x() {
+            sprites.sort_by_key(|sprite| sprite.x);
+        }
+        let mut obj_line: [Option<ObjPixel>; SCREEN_WIDTH] = [None; SCREEN_WIDTH];
+        for sprite in sprites.iter().take(MAX_SPRITES_PER_LINE) {
+            let (lo, hi) = self.sprite_tile_row(sprite);
+            for i in 0..8u8 {
+                let x = sprite.x as i16 - 8 + i as i16;
+                if !(0..SCREEN_WIDTH as i16).contains(&x) {
+                    continue;
+                }
+                let color = color_id(lo, hi, 7 - i);
+                let slot = &mut obj_line[x as usize];
+                if color != 0 && slot.is_none() {
+                    *slot = Some(ObjPixel {
+                        color,
+                        flags: sprite.flags,
+                        index: sprite.index,
+                    });
+                }
+            }
+        }
+
+        let offset = self.ly as usize * SCREEN_WIDTH;
+        for x in 0..SCREEN_WIDTH {
+            self.framebuffer[offset + x] = self.mix_pixel(bg_line[x], obj_line[x]);
+        }
+    }
+}
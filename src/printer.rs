@@ -0,0 +1,384 @@
+// https://gbdev.io/pandocs/Gameboy_Printer.html
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+
+use crate::serial::SerialDevice;
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const DEVICE_ID: u8 = 0x81;
+
+const CMD_INIT: u8 = 0x01;
+const CMD_PRINT: u8 = 0x02;
+const CMD_DATA: u8 = 0x04;
+const CMD_STATUS: u8 = 0x0f;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0b0000_0001;
+const STATUS_PRINTING: u8 = 0b0000_0010;
+const STATUS_IMAGE_FULL: u8 = 0b0000_0100;
+const STATUS_UNPROCESSED: u8 = 0b0000_1000;
+
+const WIDTH: usize = 160;
+const TILES_PER_ROW: usize = WIDTH / 8;
+/// The printer's RAM holds 9 data packets, 2 rows of 20 tiles each.
+const BUFFER_SIZE: usize = 9 * 2 * TILES_PER_ROW * 16;
+/// Status packets answered with the busy bit set after each print.
+const PRINT_STATUS_POLLS: u8 = 4;
+
+const SHADES: [u8; 4] = [0xff, 0xaa, 0x55, 0x00];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    DeviceId,
+    Status,
+}
+
+/// A Game Boy Printer on the serial port that writes each printed page to
+/// `<dir>/page-NNN.png`.
+///
+/// A page is everything printed until a print command with a non-zero
+/// bottom margin feeds the paper out.
+pub struct Printer {
+    dir: PathBuf,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    status: u8,
+    busy_polls: u8,
+    buffer: Vec<u8>,
+    page: Vec<u8>,
+    pages: usize,
+}
+
+impl Printer {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            state: State::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            status: 0,
+            busy_polls: 0,
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            page: Vec::new(),
+            pages: 0,
+        }
+    }
+
+    /// Number of pages written so far.
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    fn process_packet(&mut self) {
+        if self.checksum != self.received_checksum {
+            self.status |= STATUS_CHECKSUM_ERROR;
+            return;
+        }
+        self.status &= !STATUS_CHECKSUM_ERROR;
+
+        match self.command {
+            CMD_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+            }
+            CMD_DATA => {
+                let data = std::mem::take(&mut self.data);
+                if self.compressed {
+                    decompress(&data, &mut self.buffer);
+                } else {
+                    self.buffer.extend_from_slice(&data);
+                }
+                self.buffer.truncate(BUFFER_SIZE);
+                if !self.buffer.is_empty() {
+                    self.status |= STATUS_UNPROCESSED;
+                }
+                if self.buffer.len() == BUFFER_SIZE {
+                    self.status |= STATUS_IMAGE_FULL;
+                }
+            }
+            CMD_PRINT if self.data.len() == 4 => {
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+                if sheets > 0 {
+                    self.print(palette);
+                }
+                if margins & 0x0f != 0 {
+                    // Failing to save a page shouldn't take the game down.
+                    if let Err(err) = self.feed_page() {
+                        log::error!(target: "serial", "Failed to save printed page: {}", err);
+                    }
+                }
+                self.buffer.clear();
+                self.status =
+                    (self.status & !(STATUS_UNPROCESSED | STATUS_IMAGE_FULL)) | STATUS_PRINTING;
+                self.busy_polls = PRINT_STATUS_POLLS;
+            }
+            _ => {}
+        }
+    }
+
+    /// Appends the tiles in the buffer to the current page as shades.
+    fn print(&mut self, palette: u8) {
+        // A palette of 0 is treated like the usual 0xE4.
+        let palette = if palette == 0 { 0xe4 } else { palette };
+        let rows = self.buffer.len() / (TILES_PER_ROW * 16);
+        for tile_row in 0..rows {
+            for y in 0..8 {
+                for x in 0..WIDTH {
+                    let tile = tile_row * TILES_PER_ROW + x / 8;
+                    let offset = tile * 16 + y * 2;
+                    let (lo, hi) = (self.buffer[offset], self.buffer[offset + 1]);
+                    let bit = 7 - (x % 8);
+                    let color = (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1);
+                    let shade = (palette >> (color * 2)) & 0b11;
+                    self.page.push(SHADES[shade as usize]);
+                }
+            }
+        }
+    }
+
+    fn feed_page(&mut self) -> io::Result<()> {
+        if self.page.is_empty() {
+            return Ok(());
+        }
+        let page = std::mem::take(&mut self.page);
+        self.pages += 1;
+
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(format!("page-{:03}.png", self.pages));
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(File::create(path)?),
+            WIDTH as u32,
+            (page.len() / WIDTH) as u32,
+        );
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&page)?;
+        Ok(())
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut response = 0x00;
+        self.state = match self.state {
+            State::Magic(i) if byte == MAGIC[i] => {
+                if i + 1 < MAGIC.len() {
+                    State::Magic(i + 1)
+                } else {
+                    State::Command
+                }
+            }
+            // The byte that broke the sequence may start the next one.
+            State::Magic(_) if byte == MAGIC[0] => State::Magic(1),
+            State::Magic(_) => State::Magic(0),
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthLow
+            }
+            State::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::LengthHigh
+            }
+            State::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize {
+                    State::ChecksumLow
+                } else {
+                    State::Data
+                }
+            }
+            State::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                State::ChecksumHigh
+            }
+            State::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                State::DeviceId
+            }
+            State::DeviceId => {
+                response = DEVICE_ID;
+                self.process_packet();
+                State::Status
+            }
+            State::Status => {
+                response = self.status;
+                if self.command == CMD_STATUS && self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+                    if self.busy_polls == 0 {
+                        self.status &= !STATUS_PRINTING;
+                    }
+                }
+                State::Magic(0)
+            }
+        };
+        response
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        // Whatever is still on the paper when the printer goes away is a page.
+        if let Err(err) = self.feed_page() {
+            log::error!(target: "serial", "Failed to save printed page: {}", err);
+        }
+    }
+}
+
+/// Expands the printer's run-length encoding: a control byte with bit 7 set
+/// repeats the next byte `(control & 0x7F) + 2` times, otherwise the next
+/// `control + 1` bytes are copied as they are.
+fn decompress(data: &[u8], out: &mut Vec<u8>) {
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            let Some(&byte) = data.get(i) else { break };
+            out.extend(std::iter::repeat_n(byte, (control & 0x7f) as usize + 2));
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send_packet(printer: &mut Printer, command: u8, compressed: bool, data: &[u8]) -> u8 {
+        let mut packet = vec![command, compressed as u8];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(data);
+        let checksum = packet
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+
+        for &byte in MAGIC.iter().chain(&packet).chain(&checksum.to_le_bytes()) {
+            assert_eq!(printer.exchange(byte), 0x00);
+        }
+        assert_eq!(printer.exchange(0x00), DEVICE_ID);
+        printer.exchange(0x00)
+    }
+
+    #[test]
+    fn test_decompress() {
+        let mut out = Vec::new();
+        decompress(&[0x81, 0xaa, 0x01, 0x12, 0x34], &mut out);
+        assert_eq!(out, [0xaa, 0xaa, 0xaa, 0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_print_page() {
+        let dir = std::env::temp_dir().join(format!("rust-gb-printer-{}", std::process::id()));
+        let mut printer = Printer::new(&dir);
+
+        assert_eq!(send_packet(&mut printer, CMD_INIT, false, &[]), 0x00);
+        let row = [0xffu8; TILES_PER_ROW * 16];
+        assert_eq!(
+            send_packet(&mut printer, CMD_DATA, false, &row),
+            STATUS_UNPROCESSED
+        );
+        assert_eq!(
+            send_packet(
+                &mut printer,
+                CMD_DATA,
+                true,
+                &[0xff, 0x00, 0xff, 0x00, 0xbc, 0x00]
+            ),
+            STATUS_UNPROCESSED
+        );
+        assert_eq!(
+            send_packet(&mut printer, CMD_DATA, false, &[]),
+            STATUS_UNPROCESSED
+        );
+        assert_eq!(
+            send_packet(&mut printer, CMD_PRINT, false, &[1, 0x13, 0xe4, 0x40]),
+            STATUS_PRINTING
+        );
+        assert_eq!(printer.pages(), 1);
+        assert_eq!(printer.page.len(), 0);
+
+        let mut status = STATUS_PRINTING;
+        for _ in 0..PRINT_STATUS_POLLS {
+            status = send_packet(&mut printer, CMD_STATUS, false, &[]);
+        }
+        assert_eq!(status, STATUS_PRINTING);
+        assert_eq!(send_packet(&mut printer, CMD_STATUS, false, &[]), 0x00);
+
+        let decoder = png::Decoder::new(File::open(dir.join("page-001.png")).unwrap());
+        let reader = decoder.read_info().unwrap();
+        assert_eq!(reader.info().width, WIDTH as u32);
+        assert_eq!(reader.info().height, 16);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_image_full() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        let packet = [0u8; 2 * TILES_PER_ROW * 16];
+        for _ in 0..8 {
+            assert_eq!(
+                send_packet(&mut printer, CMD_DATA, false, &packet),
+                STATUS_UNPROCESSED
+            );
+        }
+        assert_eq!(
+            send_packet(&mut printer, CMD_DATA, false, &packet),
+            STATUS_UNPROCESSED | STATUS_IMAGE_FULL
+        );
+        assert_eq!(printer.buffer.len(), BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_checksum_error() {
+        let mut printer = Printer::new(std::env::temp_dir());
+        for &byte in &[0x88, 0x33, CMD_INIT, 0x00, 0x00, 0x00, 0x02, 0x00] {
+            printer.exchange(byte);
+        }
+        assert_eq!(printer.exchange(0x00), DEVICE_ID);
+        assert_eq!(printer.exchange(0x00), STATUS_CHECKSUM_ERROR);
+
+        // A stray 0x88 right before the next packet doesn't lose it.
+        printer.exchange(0x88);
+        assert_eq!(send_packet(&mut printer, CMD_INIT, false, &[]), 0x00);
+    }
+}
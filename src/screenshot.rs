@@ -0,0 +1,87 @@
+// https://gbdev.io/pandocs/Palettes.html#lcd-color-palettes-cgb-only
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Expands an RGB555 colour to 8 bits per channel, so 0x1F maps to 0xFF.
+pub fn rgb888(color: u16) -> [u8; 3] {
+    let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+    [
+        expand(color & 0x1f),
+        expand((color >> 5) & 0x1f),
+        expand((color >> 10) & 0x1f),
+    ]
+}
+
+/// Turns a framebuffer into RGB bytes, each pixel repeated `scale` times
+/// across and down.
+pub fn framebuffer_to_rgb(framebuffer: &[u16], scale: usize) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(framebuffer.len() * scale * scale * 3);
+    for row in framebuffer.chunks(SCREEN_WIDTH) {
+        let line: Vec<u8> = row
+            .iter()
+            .flat_map(|&color| std::iter::repeat_n(rgb888(color), scale).flatten())
+            .collect();
+        for _ in 0..scale {
+            rgb.extend_from_slice(&line);
+        }
+    }
+    rgb
+}
+
+/// Encodes a framebuffer as an RGB PNG scaled up by `scale`, which must be
+/// at least 1.
+pub fn write_png<W: Write>(writer: W, framebuffer: &[u16], scale: usize) -> io::Result<()> {
+    assert!(scale >= 1, "screenshot scale must be at least 1");
+    let mut encoder = png::Encoder::new(
+        writer,
+        (SCREEN_WIDTH * scale) as u32,
+        (SCREEN_HEIGHT * scale) as u32,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&framebuffer_to_rgb(framebuffer, scale))?;
+    Ok(())
+}
+
+pub fn save_png<P: AsRef<Path>>(path: P, framebuffer: &[u16], scale: usize) -> io::Result<()> {
+    write_png(BufWriter::new(File::create(path)?), framebuffer, scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::DMG_COLORS;
+
+    #[test]
+    fn test_rgb888() {
+        assert_eq!(rgb888(DMG_COLORS[0]), [0xff, 0xff, 0xff]);
+        assert_eq!(rgb888(DMG_COLORS[3]), [0x00, 0x00, 0x00]);
+        assert_eq!(rgb888(0x001f), [0xff, 0x00, 0x00]);
+        assert_eq!(rgb888(0x7c00), [0x00, 0x00, 0xff]);
+    }
+
+    #[test]
+    fn test_scaled_png() {
+        let mut framebuffer = vec![DMG_COLORS[0]; SCREEN_WIDTH * SCREEN_HEIGHT];
+        framebuffer[1] = 0x03e0;
+        let mut bytes = Vec::new();
+        write_png(&mut bytes, &framebuffer, 2).unwrap();
+
+        let decoder = png::Decoder::new(bytes.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (320, 288));
+        let stride = 320 * 3;
+        assert_eq!(&pixels[0..3], &[0xff, 0xff, 0xff]);
+        // The green pixel covers x 2-3 on both of the first two rows.
+        for offset in [6, 9, stride + 6, stride + 9] {
+            assert_eq!(&pixels[offset..offset + 3], &[0x00, 0xff, 0x00]);
+        }
+        assert_eq!(&pixels[12..15], &[0xff, 0xff, 0xff]);
+    }
+}
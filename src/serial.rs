@@ -0,0 +1,197 @@
+// https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html
+use std::io::Write;
+
+use crate::interrupt;
+
+const SC_TRANSFER: u8 = 0b1000_0000;
+const SC_FAST_CLOCK: u8 = 0b0000_0010;
+const SC_INTERNAL_CLOCK: u8 = 0b0000_0001;
+
+/// CPU cycles per bit on the internal clock: 8192 Hz, or 262144 Hz with the
+/// CGB fast clock. Both double along with the CPU in double speed mode.
+const CYCLES_PER_BIT: u32 = 512;
+const FAST_CYCLES_PER_BIT: u32 = 16;
+
+/// Whatever is plugged into the link port.
+pub trait SerialDevice {
+    /// Called when the Game Boy has clocked out `byte` on its internal clock.
+    /// Returns the byte clocked in from the device at the same time.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    /// Called on every tick. `waiting` holds SB while the Game Boy has a
+    /// transfer armed on the external clock. Returns the byte the device
+    /// clocked in, which completes that transfer.
+    fn poll(&mut self, waiting: Option<u8>) -> Option<u8> {
+        let _ = waiting;
+        None
+    }
+}
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    cgb: bool,
+    /// Cycles left until the byte in flight on the internal clock is done.
+    cycles: u32,
+    device: Option<Box<dyn SerialDevice>>,
+    output: Vec<u8>,
+    echo: bool,
+    interrupts: u8,
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            sb: 0x00,
+            sc: 0x00,
+            cgb: false,
+            cycles: 0,
+            device: None,
+            output: Vec::new(),
+            echo: false,
+            interrupts: 0,
+        }
+    }
+
+    pub fn set_cgb_mode(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = Some(device);
+    }
+
+    pub fn disconnect(&mut self) -> Option<Box<dyn SerialDevice>> {
+        self.device.take()
+    }
+
+    /// Also prints every byte sent to stdout as it goes out.
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// Every byte the Game Boy has sent so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Returns and clears the interrupt flags requested since the last call.
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff01 => self.sb,
+            0xff02 => {
+                let unused = if self.cgb { 0x7c } else { 0x7e };
+                unused | self.sc
+            }
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0xff01 => self.sb = byte,
+            0xff02 => {
+                let mask = if self.cgb {
+                    SC_TRANSFER | SC_FAST_CLOCK | SC_INTERNAL_CLOCK
+                } else {
+                    SC_TRANSFER | SC_INTERNAL_CLOCK
+                };
+                self.sc = byte & mask;
+                self.cycles = if self.sc & SC_TRANSFER != 0 && self.sc & SC_INTERNAL_CLOCK != 0 {
+                    self.cycles_per_bit() * 8
+                } else {
+                    0
+                };
+            }
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if self.cycles > 0 {
+            self.cycles = self.cycles.saturating_sub(cycles);
+            if self.cycles == 0 {
+                // With nothing plugged in the line stays high.
+                let received = match &mut self.device {
+                    Some(device) => device.exchange(self.sb),
+                    None => 0xff,
+                };
+                self.finish_transfer(received);
+            }
+            return;
+        }
+
+        let waiting = if self.sc & (SC_TRANSFER | SC_INTERNAL_CLOCK) == SC_TRANSFER {
+            Some(self.sb)
+        } else {
+            None
+        };
+        if let Some(device) = &mut self.device {
+            if let Some(received) = device.poll(waiting) {
+                self.finish_transfer(received);
+            }
+        }
+    }
+
+    fn cycles_per_bit(&self) -> u32 {
+        if self.sc & SC_FAST_CLOCK != 0 {
+            FAST_CYCLES_PER_BIT
+        } else {
+            CYCLES_PER_BIT
+        }
+    }
+
+    fn finish_transfer(&mut self, received: u8) {
+        log::debug!(target: "serial", "Sent {:02X}, received {:02X}", self.sb, received);
+        self.output.push(self.sb);
+        if self.echo {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&[self.sb]).unwrap();
+            stdout.flush().unwrap();
+        }
+        self.sb = received;
+        self.sc &= !SC_TRANSFER;
+        self.interrupts |= interrupt::SERIAL;
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internal_clock_transfer() {
+        let mut serial = Serial::new();
+        for &byte in b"ok" {
+            serial.write_register(0xff01, byte);
+            serial.write_register(0xff02, SC_TRANSFER | SC_INTERNAL_CLOCK);
+            serial.tick(CYCLES_PER_BIT * 8 - 4);
+            assert_eq!(serial.read_register(0xff02), 0xff);
+            serial.tick(4);
+            assert_eq!(serial.read_register(0xff02), 0x7f);
+            assert_eq!(serial.read_register(0xff01), 0xff);
+            assert_eq!(serial.take_interrupts(), interrupt::SERIAL);
+        }
+        assert_eq!(serial.output(), b"ok");
+    }
+
+    #[test]
+    fn test_external_clock_waits() {
+        let mut serial = Serial::new();
+        serial.write_register(0xff01, 0x42);
+        serial.write_register(0xff02, SC_TRANSFER);
+        serial.tick(CYCLES_PER_BIT * 64);
+        assert_eq!(serial.read_register(0xff02), 0xfe);
+        assert!(serial.output().is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+// https://gbdev.io/pandocs/Timer_and_Divider_Registers.html
+use crate::interrupt;
+
+const TAC_ENABLE: u8 = 0b0000_0100;
+const TAC_CLOCK: u8 = 0b0000_0011;
+
+pub struct Timer {
+    /// DIV is the upper byte of this counter, which advances every cycle.
+    counter: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    double_speed: bool,
+    /// Falling edges of the DIV bit that clocks the APU frame sequencer.
+    apu_clocks: u32,
+    interrupts: u8,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self {
+            counter: 0xabcc,
+            tima: 0x00,
+            tma: 0x00,
+            tac: 0xf8,
+            double_speed: false,
+            apu_clocks: 0,
+            interrupts: 0,
+        }
+    }
+
+    /// Returns and clears the interrupt flags requested since the last call.
+    pub fn take_interrupts(&mut self) -> u8 {
+        std::mem::take(&mut self.interrupts)
+    }
+
+    /// Returns and clears the number of times the APU frame sequencer has
+    /// been clocked since the last call.
+    pub fn take_apu_clocks(&mut self) -> u32 {
+        std::mem::take(&mut self.apu_clocks)
+    }
+
+    /// In double speed mode DIV runs twice as fast, so the frame sequencer
+    /// watches the next bit up to keep its 512 Hz.
+    pub fn set_double_speed(&mut self, double_speed: bool) {
+        self.double_speed = double_speed;
+    }
+
+    /// Resets DIV, as done by a write to it or by a CGB speed switch.
+    pub fn reset_div(&mut self) {
+        self.set_counter(0);
+    }
+
+    pub fn read_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff04 => (self.counter >> 8) as u8,
+            0xff05 => self.tima,
+            0xff06 => self.tma,
+            0xff07 => 0xf8 | self.tac,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0xff04 => self.reset_div(),
+            0xff05 => self.tima = byte,
+            0xff06 => self.tma = byte,
+            0xff07 => {
+                let was_high = self.timer_bit();
+                self.tac = byte & (TAC_ENABLE | TAC_CLOCK);
+                // Disabling the timer or picking another bit can produce the
+                // same falling edge a counter increment would.
+                if was_high && !self.timer_bit() {
+                    self.increment_tima();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.set_counter(self.counter.wrapping_add(1));
+        }
+    }
+
+    fn set_counter(&mut self, counter: u16) {
+        let was_high = self.timer_bit();
+        let apu_was_high = self.apu_bit();
+        self.counter = counter;
+        if was_high && !self.timer_bit() {
+            self.increment_tima();
+        }
+        if apu_was_high && !self.apu_bit() {
+            self.apu_clocks += 1;
+        }
+    }
+
+    /// Bit 4 of DIV, or bit 5 in double speed mode.
+    fn apu_bit(&self) -> bool {
+        let bit = if self.double_speed { 13 } else { 12 };
+        self.counter & (1 << bit) != 0
+    }
+
+    /// TIMA is clocked by the falling edge of this counter bit ANDed with the
+    /// enable flag.
+    fn timer_bit(&self) -> bool {
+        let bit = match self.tac & TAC_CLOCK {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & TAC_ENABLE != 0 && self.counter & (1 << bit) != 0
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        if overflow {
+            log::trace!(target: "timer", "TIMA overflowed, reloading {:02X}", self.tma);
+            self.tima = self.tma;
+            self.interrupts |= interrupt::TIMER;
+        } else {
+            self.tima = tima;
+        }
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tima_overflow() {
+        let mut timer = Timer::new();
+        timer.write_register(0xff04, 0);
+        timer.write_register(0xff06, 0xf0);
+        timer.write_register(0xff05, 0xfe);
+        timer.write_register(0xff07, TAC_ENABLE | 0b01);
+
+        timer.tick(16);
+        assert_eq!(timer.read_register(0xff05), 0xff);
+        assert_eq!(timer.take_interrupts(), 0);
+
+        timer.tick(16);
+        assert_eq!(timer.read_register(0xff05), 0xf0);
+        assert_eq!(timer.take_interrupts(), interrupt::TIMER);
+    }
+
+    #[test]
+    fn test_div_write_resets_counter() {
+        let mut timer = Timer::new();
+        timer.tick(0x1234);
+        timer.write_register(0xff04, 0x56);
+        assert_eq!(timer.read_register(0xff04), 0);
+        timer.tick(256);
+        assert_eq!(timer.read_register(0xff04), 1);
+    }
+
+    #[test]
+    fn test_apu_clocks_at_512_hz() {
+        let mut timer = Timer::new();
+        timer.reset_div();
+        timer.take_apu_clocks();
+        timer.tick(4 * 8192);
+        assert_eq!(timer.take_apu_clocks(), 4);
+
+        timer.set_double_speed(true);
+        timer.reset_div();
+        timer.tick(4 * 8192);
+        assert_eq!(timer.take_apu_clocks(), 2);
+    }
+}
@@ -0,0 +1,408 @@
+// https://github.com/robert/gameboy-doctor
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead};
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::gameboy::{GameBoy, CYCLES_PER_FRAME};
+use crate::harness::panic_message;
+
+/// How long to wait out a HALT before giving up on it: reference logs skip
+/// the time spent halted, but a HALT nothing wakes from never ends.
+const HALT_TIMEOUT: u64 = CYCLES_PER_FRAME * 60;
+
+const FLAGS: [(char, u8); 4] = [('Z', 0x80), ('N', 0x40), ('H', 0x20), ('C', 0x10)];
+
+/// The CPU state before one instruction, as logged in a trace.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TraceState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    /// Gameboy Doctor logs include the four bytes at PC, op_log doesn't.
+    pub pcmem: Option<[u8; 4]>,
+}
+
+impl TraceState {
+    /// Reads a Gameboy Doctor line (`A:01 F:B0 ... PCMEM:00,C3,13,02`) or
+    /// an op_log line (`01:0150  ... af=01B0 bc=0013 de=00D8 ...`) as
+    /// logged or dumped from the history. Anything else, like blank lines,
+    /// gives `None`.
+    pub fn parse(line: &str) -> Option<Self> {
+        if line.contains("PCMEM:") {
+            Self::parse_doctor(line)
+        } else {
+            Self::parse_op_log(line)
+        }
+    }
+
+    fn parse_doctor(line: &str) -> Option<Self> {
+        let field = |name: &str| {
+            line.split_whitespace()
+                .find_map(|token| token.strip_prefix(name)?.strip_prefix(':'))
+        };
+        let byte = |name: &str| u8::from_str_radix(field(name)?, 16).ok();
+        let word = |name: &str| u16::from_str_radix(field(name)?, 16).ok();
+
+        let mut pcmem = [0; 4];
+        let mut bytes = field("PCMEM")?.split(',');
+        for value in pcmem.iter_mut() {
+            *value = u8::from_str_radix(bytes.next()?, 16).ok()?;
+        }
+        Some(Self {
+            a: byte("A")?,
+            f: byte("F")?,
+            b: byte("B")?,
+            c: byte("C")?,
+            d: byte("D")?,
+            e: byte("E")?,
+            h: byte("H")?,
+            l: byte("L")?,
+            sp: word("SP")?,
+            pc: word("PC")?,
+            pcmem: Some(pcmem),
+        })
+    }
+
+    fn parse_op_log(line: &str) -> Option<Self> {
+        let word = |names: &[&str]| {
+            line.split_whitespace().find_map(|token| {
+                let (name, value) = token.split_once('=')?;
+                if !names.contains(&name) {
+                    return None;
+                }
+                u16::from_str_radix(value, 16).ok()
+            })
+        };
+        let [a, f] = word(&["af"])?.to_be_bytes();
+        let [b, c] = word(&["bc"])?.to_be_bytes();
+        let [d, e] = word(&["de", "dw"])?.to_be_bytes();
+        let [h, l] = word(&["hl"])?.to_be_bytes();
+        // Logs from before DE was labelled properly call it dw, and logged
+        // PC as 0x100 less than it was.
+        let pc_offset = if word(&["dw"]).is_some() { 0x100 } else { 0 };
+        // Older logs end with pc=, newer ones start with the bank and PC.
+        let pc = word(&["pc"])
+            .map(|pc| pc.wrapping_add(pc_offset))
+            .or_else(|| {
+                line.split_whitespace().find_map(|token| {
+                    let (bank, pc) = token.split_once(':')?;
+                    if bank.len() != 2 || pc.len() != 4 {
+                        return None;
+                    }
+                    u8::from_str_radix(bank, 16).ok()?;
+                    u16::from_str_radix(pc, 16).ok()
+                })
+            })?;
+        Some(Self {
+            a,
+            f,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
+            sp: word(&["sp"])?,
+            pc,
+            pcmem: None,
+        })
+    }
+
+    /// Describes every register, flag and PCMEM byte that differs, as
+    /// `name expected != actual`.
+    pub fn differences(&self, actual: &TraceState) -> Vec<String> {
+        let mut diffs = Vec::new();
+        let bytes = [
+            ("A", self.a, actual.a),
+            ("F", self.f, actual.f),
+            ("B", self.b, actual.b),
+            ("C", self.c, actual.c),
+            ("D", self.d, actual.d),
+            ("E", self.e, actual.e),
+            ("H", self.h, actual.h),
+            ("L", self.l, actual.l),
+        ];
+        for (name, expected, got) in bytes {
+            if expected != got {
+                diffs.push(format!("{} {:02X} != {:02X}", name, expected, got));
+            }
+        }
+        for (flag, mask) in FLAGS {
+            if self.f & mask != actual.f & mask {
+                let set = |f: u8| if f & mask != 0 { "set" } else { "clear" };
+                diffs.push(format!(
+                    "flag {} {} != {}",
+                    flag,
+                    set(self.f),
+                    set(actual.f)
+                ));
+            }
+        }
+        for (name, expected, got) in [("SP", self.sp, actual.sp), ("PC", self.pc, actual.pc)] {
+            if expected != got {
+                diffs.push(format!("{} {:04X} != {:04X}", name, expected, got));
+            }
+        }
+        if let (Some(expected), Some(got)) = (self.pcmem, actual.pcmem) {
+            if expected != got {
+                diffs.push(format!("PCMEM {:02X?} != {:02X?}", expected, got));
+            }
+        }
+        diffs
+    }
+}
+
+/// Where a run first disagreed with a reference trace.
+pub struct Divergence {
+    /// 1-based line in the reference trace.
+    pub line: usize,
+    /// The instructions before it, which all matched.
+    pub before: Vec<String>,
+    pub expected: String,
+    /// What the emulator had instead, or why it couldn't carry on.
+    pub actual: String,
+    pub differences: Vec<String>,
+    /// The reference lines after it.
+    pub after: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Diverged at reference line {}", self.line)?;
+        for line in &self.before {
+            writeln!(f, "    {}", line)?;
+        }
+        writeln!(f, "exp {}", self.expected)?;
+        writeln!(f, "got {}", self.actual)?;
+        for line in &self.after {
+            writeln!(f, "    {}", line)?;
+        }
+        for difference in &self.differences {
+            writeln!(f, "  {}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+/// How a run compared with a reference trace.
+pub enum Comparison {
+    /// Every trace line matched. `skipped` counts the other lines, which
+    /// weren't in a format that could be read.
+    Matched {
+        lines: usize,
+        skipped: usize,
+    },
+    Diverged(Divergence),
+}
+
+/// Runs `gb` in lockstep with `reference`, one instruction per line, and
+/// returns the first instruction it disagrees on with `context` lines on
+/// either side. Blank lines and lines that aren't traces are skipped, but a
+/// reference without a single trace line is an error.
+pub fn find_divergence<R: BufRead>(
+    gb: &mut GameBoy,
+    reference: R,
+    context: usize,
+) -> io::Result<Comparison> {
+    gb.cpu_mut().set_doctor_ly(true);
+    let mut before = VecDeque::with_capacity(context + 1);
+    let mut lines = reference.lines().enumerate();
+    let mut matched = 0;
+    let mut skipped = 0;
+
+    while let Some((index, line)) = lines.next() {
+        let line = line?;
+        let Some(expected) = TraceState::parse(&line) else {
+            if !line.trim().is_empty() {
+                skipped += 1;
+            }
+            continue;
+        };
+
+        let actual_line = gb.cpu_mut().doctor_line();
+        let actual = TraceState::parse(&actual_line).expect("doctor line always parses");
+        let differences = expected.differences(&actual);
+        let failure = if differences.is_empty() {
+            execute(gb).err()
+        } else {
+            Some(actual_line.clone())
+        };
+
+        if let Some(actual) = failure {
+            let after = lines
+                .by_ref()
+                .take(context)
+                .map(|(_, line)| line)
+                .collect::<io::Result<_>>()?;
+            return Ok(Comparison::Diverged(Divergence {
+                line: index + 1,
+                before: before.into(),
+                expected: line,
+                actual,
+                differences,
+                after,
+            }));
+        }
+
+        matched += 1;
+        if before.len() == context {
+            before.pop_front();
+        }
+        if context > 0 {
+            before.push_back(line);
+        }
+    }
+    if matched == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("none of {} lines is a trace line", skipped),
+        ));
+    }
+    Ok(Comparison::Matched {
+        lines: matched,
+        skipped,
+    })
+}
+
+/// Runs one instruction, then waits out any HALT it entered. Fails with a
+/// description of what went wrong instead of panicking.
+fn execute(gb: &mut GameBoy) -> Result<(), String> {
+    let run = panic::catch_unwind(AssertUnwindSafe(|| {
+        gb.step();
+        let timeout = gb.cycles() + HALT_TIMEOUT;
+        while gb.cpu().is_halted() && gb.cycles() < timeout {
+            gb.step();
+        }
+    }));
+    run.map_err(|panic| panic_message(&panic))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::test_rom;
+
+    const BOOT: &str = "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:44,F0,44,D3";
+
+    #[test]
+    fn test_parse_formats() {
+        let doctor = TraceState::parse(BOOT).unwrap();
+        let op_log = TraceState::parse(
+            "44       LD B, H    af=01B0 bc=0013 dw=00D8 hl=014D sp=FFFE pc=0000",
+        )
+        .unwrap();
+        let history = TraceState::parse(
+            "[TRACE cpu] 01:0100  44       LD B, H    af=01B0 bc=0013 de=00D8 hl=014D sp=FFFE",
+        )
+        .unwrap();
+        assert_eq!(doctor.pcmem, Some([0x44, 0xf0, 0x44, 0xd3]));
+        let without_pcmem = TraceState {
+            pcmem: None,
+            ..doctor
+        };
+        assert_eq!(without_pcmem, op_log);
+        assert_eq!(without_pcmem, history);
+        assert_eq!(TraceState::parse("ROM size: 4 bytes"), None);
+
+        let wrong = TraceState {
+            f: 0x80,
+            c: 0x14,
+            ..doctor
+        };
+        assert_eq!(
+            doctor.differences(&wrong),
+            vec![
+                "F B0 != 80",
+                "C 13 != 14",
+                "flag H set != clear",
+                "flag C set != clear"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_divergence() {
+        // LD B,H / LDH A,(0x44) / an invalid opcode, where LY reads 0x90.
+        let rom = test_rom(&[0x44, 0xf0, 0x44, 0xd3]);
+        let reference = [
+            BOOT,
+            "A:01 F:B0 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:F0,44,D3,00",
+            "A:90 F:B0 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:D3,00,00,00",
+            "A:90 F:B0 B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0104 PCMEM:00,00,00,00",
+        ]
+        .join("\n");
+        let mut gb = GameBoy::new();
+        gb.load_cartridge(rom.clone()).unwrap();
+        let Comparison::Diverged(divergence) =
+            find_divergence(&mut gb, reference.as_bytes(), 1).unwrap()
+        else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(divergence.line, 3);
+        assert_eq!(divergence.before.len(), 1);
+        assert_eq!(divergence.actual, "panicked: Invalid opcode: 0xD3");
+        assert_eq!(divergence.after.len(), 1);
+
+        let reference = reference.replace(
+            "B:01 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101",
+            "B:02 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101",
+        );
+        let mut gb = GameBoy::new();
+        gb.load_cartridge(rom.clone()).unwrap();
+        let Comparison::Diverged(divergence) =
+            find_divergence(&mut gb, reference.as_bytes(), 0).unwrap()
+        else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(divergence.line, 2);
+        assert!(divergence.before.is_empty());
+        assert_eq!(divergence.differences, vec!["B 02 != 01"]);
+    }
+
+    #[test]
+    fn test_dw_era_op_log() {
+        // What op_log printed for this ROM before DE was relabelled.
+        let reference = "ROM size: 4 bytes
+44       LD B, H    af=01B0 bc=0013 dw=00D8 hl=014D sp=FFFE pc=0000
+F0 44    LDH A, (n) af=01B0 bc=0113 dw=00D8 hl=014D sp=FFFE pc=0001
+";
+        let mut gb = GameBoy::new();
+        gb.load_cartridge(test_rom(&[0x44, 0xf0, 0x44, 0xd3]))
+            .unwrap();
+        assert!(matches!(
+            find_divergence(&mut gb, reference.as_bytes(), 0).unwrap(),
+            Comparison::Matched {
+                lines: 2,
+                skipped: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn test_reference_without_traces() {
+        let rom = test_rom(&[0x44, 0xf0, 0x44, 0xd3]);
+        let mut gb = GameBoy::new();
+        gb.load_cartridge(rom.clone()).unwrap();
+        let reference = format!("header\n\n{}\n", BOOT);
+        assert!(matches!(
+            find_divergence(&mut gb, reference.as_bytes(), 0).unwrap(),
+            Comparison::Matched {
+                lines: 1,
+                skipped: 1
+            }
+        ));
+
+        let mut gb = GameBoy::new();
+        gb.load_cartridge(rom).unwrap();
+        let reference = "[2024-01-01T00:00:00Z TRACE cpu] 44 LD B, H\n";
+        assert!(find_divergence(&mut gb, reference.as_bytes(), 0).is_err());
+    }
+}
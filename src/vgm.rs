@@ -0,0 +1,209 @@
+// https://vgmrips.net/wiki/VGM_Specification
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::apu::{RegisterWrite, CLOCK_RATE};
+use crate::cpu::Cpu;
+
+const VERSION: u32 = 0x0000_0161;
+/// Commands start right after the 1.61 header.
+const DATA_START: u32 = 0x100;
+/// VGM time is counted in samples at this rate, whatever the chip.
+const VGM_RATE: u64 = 44_100;
+
+const CMD_GB_WRITE: u8 = 0xb3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC_FRAME: u8 = 0x62;
+const CMD_WAIT_PAL_FRAME: u8 = 0x63;
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_END: u8 = 0x66;
+
+/// Logs every APU register write of a running Game Boy to a VGM file.
+///
+/// Like `WavWriter`, the header and end marker are rewritten on every
+/// `record`, so the file stays valid if the emulator never exits cleanly.
+pub struct VgmRecorder {
+    file: BufWriter<File>,
+    /// Bytes of commands written after the header.
+    data_size: u32,
+    /// VGM samples waited for so far.
+    samples: u64,
+    /// The APU cycle recording started at.
+    start: u64,
+    loop_at: Option<u64>,
+    /// Data offset and sample count where the loop starts.
+    loop_point: Option<(u32, u64)>,
+}
+
+impl VgmRecorder {
+    /// Starts logging `cpu`'s APU writes to `path`, beginning with the
+    /// writes needed to get a freshly reset APU into its current state.
+    pub fn create<P: AsRef<Path>>(cpu: &mut Cpu, path: P) -> io::Result<Self> {
+        let mut recorder = Self {
+            file: BufWriter::new(File::create(path)?),
+            data_size: 0,
+            samples: 0,
+            start: cpu.apu().cycles(),
+            loop_at: None,
+            loop_point: None,
+        };
+        recorder.file.write_all(&[0; DATA_START as usize])?;
+
+        let power = cpu.apu().read_register(0xff26) & 0x80;
+        let (registers, wave_ram) = cpu.apu().raw_registers();
+        recorder.write_command(&[CMD_GB_WRITE, 0x16, power])?;
+        for (i, &value) in registers.iter().enumerate() {
+            // Replaying a trigger would restart the channel.
+            let value = if i % 5 == 4 { value & 0x7f } else { value };
+            recorder.write_command(&[CMD_GB_WRITE, i as u8, value])?;
+        }
+        for (i, &value) in wave_ram.iter().enumerate() {
+            recorder.write_command(&[CMD_GB_WRITE, 0x20 + i as u8, value])?;
+        }
+
+        cpu.apu_mut().set_write_logging(true);
+        recorder.finish_chunk()?;
+        Ok(recorder)
+    }
+
+    /// Makes the song loop back to `seconds` after the start of the
+    /// recording when played.
+    pub fn set_loop_at(&mut self, seconds: f64) {
+        self.loop_at = Some(self.start + (seconds * CLOCK_RATE as f64) as u64);
+    }
+
+    /// Records what happened so far and marks the loop point right after.
+    pub fn mark_loop(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        self.record(cpu)?;
+        self.loop_point = Some((self.data_size, self.samples));
+        self.finish_chunk()
+    }
+
+    /// Writes the register writes and time passed since the last call.
+    pub fn record(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        for write in cpu.apu_mut().take_writes() {
+            self.write_register(write)?;
+        }
+        self.wait_until(cpu.apu().cycles())?;
+        self.finish_chunk()
+    }
+
+    fn write_register(&mut self, write: RegisterWrite) -> io::Result<()> {
+        self.wait_until(write.cycle)?;
+        let reg = (write.addr - 0xff10) as u8;
+        self.write_command(&[CMD_GB_WRITE, reg, write.value])
+    }
+
+    /// Waits until APU cycle `cycle`, marking the loop on the way if it's
+    /// due.
+    fn wait_until(&mut self, cycle: u64) -> io::Result<()> {
+        if let Some(loop_at) = self.loop_at.filter(|&at| at <= cycle) {
+            self.wait_samples(self.samples_at(loop_at))?;
+            self.loop_point = Some((self.data_size, self.samples));
+            self.loop_at = None;
+        }
+        self.wait_samples(self.samples_at(cycle))
+    }
+
+    fn samples_at(&self, cycle: u64) -> u64 {
+        (cycle - self.start) * VGM_RATE / CLOCK_RATE as u64
+    }
+
+    fn wait_samples(&mut self, target: u64) -> io::Result<()> {
+        while self.samples < target {
+            let wait = (target - self.samples).min(u16::MAX as u64);
+            match wait {
+                735 => self.write_command(&[CMD_WAIT_NTSC_FRAME])?,
+                882 => self.write_command(&[CMD_WAIT_PAL_FRAME])?,
+                1..=16 => self.write_command(&[CMD_WAIT_SHORT + wait as u8 - 1])?,
+                _ => {
+                    let [low, high] = (wait as u16).to_le_bytes();
+                    self.write_command(&[CMD_WAIT, low, high])?
+                }
+            }
+            self.samples += wait;
+        }
+        Ok(())
+    }
+
+    fn write_command(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.data_size += bytes.len() as u32;
+        Ok(())
+    }
+
+    /// Ends the file after what has been written so far, to be overwritten
+    /// by the next chunk.
+    fn finish_chunk(&mut self) -> io::Result<()> {
+        self.file.write_all(&[CMD_END])?;
+        let end = DATA_START + self.data_size + 1;
+
+        let mut header = [0u8; 0x84];
+        header[0x00..0x04].copy_from_slice(b"Vgm ");
+        header[0x04..0x08].copy_from_slice(&(end - 0x04).to_le_bytes());
+        header[0x08..0x0c].copy_from_slice(&VERSION.to_le_bytes());
+        header[0x18..0x1c].copy_from_slice(&(self.samples as u32).to_le_bytes());
+        if let Some((offset, samples)) = self.loop_point {
+            let loop_offset = DATA_START + offset - 0x1c;
+            header[0x1c..0x20].copy_from_slice(&loop_offset.to_le_bytes());
+            let loop_samples = (self.samples - samples) as u32;
+            header[0x20..0x24].copy_from_slice(&loop_samples.to_le_bytes());
+        }
+        header[0x34..0x38].copy_from_slice(&(DATA_START - 0x34).to_le_bytes());
+        header[0x80..0x84].copy_from_slice(&CLOCK_RATE.to_le_bytes());
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.seek(SeekFrom::Start((end - 1) as u64))?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::test_rom;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_vgm_log() {
+        let path = std::env::temp_dir().join(format!("rust-gb-{}.vgm", std::process::id()));
+        let mut cpu = Cpu::new();
+        cpu.load_rom(test_rom(&[0x76])).unwrap();
+        let mut vgm = VgmRecorder::create(&mut cpu, &path).unwrap();
+        let setup = DATA_START as usize + 3 * (1 + 0x16 + 16);
+
+        cpu.write_u8(0xff24, 0x55);
+        while cpu.cycles() < 70224 {
+            cpu.step();
+        }
+        vgm.mark_loop(&mut cpu).unwrap();
+        cpu.write_u8(0xff30, 0x12);
+        vgm.record(&mut cpu).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&bytes[0..4], b"Vgm ");
+        assert_eq!(u32_at(&bytes, 0x04) as usize, bytes.len() - 4);
+        assert_eq!(u32_at(&bytes, 0x08), 0x161);
+        assert_eq!(u32_at(&bytes, 0x34), 0xcc);
+        assert_eq!(u32_at(&bytes, 0x80), CLOCK_RATE);
+        assert_eq!(
+            &bytes[DATA_START as usize..][..3],
+            &[CMD_GB_WRITE, 0x16, 0x80]
+        );
+
+        assert_eq!(&bytes[setup..setup + 3], &[CMD_GB_WRITE, 0x14, 0x55]);
+        assert_eq!(bytes[setup + 3], CMD_WAIT);
+        // The loop starts at the write made after the frame.
+        assert_eq!(u32_at(&bytes, 0x1c) as usize + 0x1c, setup + 6);
+        assert_eq!(&bytes[setup + 6..setup + 9], &[CMD_GB_WRITE, 0x20, 0x12]);
+        assert_eq!(u32_at(&bytes, 0x18), 70224 * 44_100 / CLOCK_RATE);
+        assert_eq!(u32_at(&bytes, 0x20), 0);
+        assert_eq!(bytes.last(), Some(&CMD_END));
+    }
+}
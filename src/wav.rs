@@ -0,0 +1,165 @@
+// http://soundfile.sapp.org/doc/WaveFormat/
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cpu::Cpu;
+
+const HEADER_SIZE: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+/// Writes 16-bit PCM WAV files.
+///
+/// The sizes in the header are brought up to date after every write, so the
+/// file stays playable even if the emulator is killed instead of exiting.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut writer = Self {
+            file: BufWriter::new(File::create(path)?),
+            data_size: 0,
+        };
+        let block_align = channels * BITS_PER_SAMPLE / 8;
+        let header = &mut writer.file;
+        header.write_all(b"RIFF")?;
+        header.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        header.write_all(b"WAVEfmt ")?;
+        header.write_all(&16u32.to_le_bytes())?;
+        header.write_all(&1u16.to_le_bytes())?;
+        header.write_all(&channels.to_le_bytes())?;
+        header.write_all(&sample_rate.to_le_bytes())?;
+        header.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        header.write_all(&block_align.to_le_bytes())?;
+        header.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+        header.write_all(b"data")?;
+        header.write_all(&0u32.to_le_bytes())?;
+        header.flush()?;
+        Ok(writer)
+    }
+
+    /// Appends interleaved samples in -1.0..=1.0, clipping anything louder.
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+        for &sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * BITS_PER_SAMPLE as u32 / 8;
+        self.update_header()
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.file.flush()
+    }
+}
+
+/// Records what a running Game Boy plays: the mixed stereo output and,
+/// optionally, each channel to its own mono file next to it.
+pub struct AudioRecorder {
+    mixed: WavWriter,
+    channels: Option<Vec<WavWriter>>,
+    buffer: Vec<f32>,
+}
+
+impl AudioRecorder {
+    /// Records to `path`, and with `split_channels` to `<stem>-ch1.wav` up
+    /// to `<stem>-ch4.wav` as well.
+    pub fn create<P: AsRef<Path>>(
+        cpu: &mut Cpu,
+        path: P,
+        split_channels: bool,
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        let rate = cpu.sample_rate();
+        let channels = if split_channels {
+            cpu.set_channel_capture(true);
+            let writers = (1..=4)
+                .map(|channel| WavWriter::create(channel_path(path, channel), 1, rate))
+                .collect::<io::Result<Vec<_>>>()?;
+            Some(writers)
+        } else {
+            None
+        };
+        Ok(Self {
+            mixed: WavWriter::create(path, 2, rate)?,
+            channels,
+            buffer: vec![0.0; 4096],
+        })
+    }
+
+    /// Writes out all the audio produced since the last call.
+    pub fn record(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        loop {
+            let count = cpu.read_samples(&mut self.buffer);
+            if count == 0 {
+                break;
+            }
+            self.mixed.write(&self.buffer[..count])?;
+        }
+
+        let Some(writers) = &mut self.channels else {
+            return Ok(());
+        };
+        for (channel, writer) in writers.iter_mut().enumerate() {
+            loop {
+                let count = cpu.read_channel_samples(channel, &mut self.buffer);
+                if count == 0 {
+                    break;
+                }
+                let mono: Vec<f32> = self.buffer[..count].iter().step_by(2).copied().collect();
+                writer.write(&mono)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn channel_path(path: &Path, channel: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}-ch{}.wav", stem, channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wav_header() {
+        let path = std::env::temp_dir().join(format!("rust-gb-{}.wav", std::process::id()));
+        let mut writer = WavWriter::create(&path, 2, 48_000).unwrap();
+        writer.write(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 8);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()), 36 + 8);
+        assert_eq!(
+            u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+            48_000
+        );
+        assert_eq!(u32::from_le_bytes(bytes[40..44].try_into().unwrap()), 8);
+        assert_eq!(&bytes[46..48], &i16::MAX.to_le_bytes());
+        assert_eq!(&bytes[50..52], &i16::MAX.to_le_bytes());
+    }
+
+    #[test]
+    fn test_channel_path() {
+        assert_eq!(
+            channel_path(Path::new("out/song.wav"), 3),
+            Path::new("out/song-ch3.wav")
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use std::env;
+use std::path::PathBuf;
+
+use rust_gb::harness::{self, Harness, Outcome};
+
+/// Runs every test ROM under `tests/roms`, or `GB_TEST_ROMS` if set. The
+/// ROMs aren't redistributable, so this only runs when asked for with
+/// `cargo test --test test_roms -- --ignored`, and fails without them.
+/// `GB_UPDATE_GOLDEN=1` records new framebuffer hashes.
+#[test]
+#[ignore = "needs test ROMs in tests/roms or GB_TEST_ROMS"]
+fn test_roms() {
+    let dir = env::var_os("GB_TEST_ROMS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/roms"));
+    assert!(dir.is_dir(), "no test ROM directory at {}", dir.display());
+
+    let mut harness = Harness::new();
+    harness.set_update_golden(env::var_os("GB_UPDATE_GOLDEN").is_some());
+    if let Some(frames) = env::var("GB_TEST_FRAMES").ok().and_then(|f| f.parse().ok()) {
+        harness.set_frame_limit(frames);
+    }
+    let results = harness.run_dir(&dir).unwrap();
+    assert!(
+        !results.is_empty(),
+        "no .gb or .gbc files in {}",
+        dir.display()
+    );
+    let table = harness::summary_table(&results);
+    println!("{}", table);
+    assert!(
+        results
+            .iter()
+            .all(|result| result.outcome.passed() || result.outcome == Outcome::Recorded),
+        "test ROMs failed:\n{}",
+        table
+    );
+}